@@ -1,217 +1,981 @@
-use std::marker::PhantomData;
-use std::ptr::NonNull;
-use std::pin::Pin;
-use std::mem;
+use std::mem::MaybeUninit;
 
-use fixed_size_allocator::FixedSizeAllocator;
-
-use crate::errors::FreeError;
-use crate::block_node_size;
+use crate::coalescing::{CoalescingPolicy, CoalescingStats};
+use crate::errors::{FreeError, IntegrityError};
+use crate::placement::Placement;
+use crate::snapshot::{ALLOCATED_LEAF_TAG, FREE_LEAF_TAG, PARENT_TAG, RESERVED_LEAF_TAG};
 
 
 /// The state of an allocation tree node.
-pub enum BlockState<'proto_alloc, const B: usize, const BLOCK_COUNT: usize> {
+#[derive(Clone, Copy)]
+pub enum BlockState {
 
     /// The node represents a free memory block.
     FreeLeaf,
 
-    // The node represents a memory block that has been split in two buddies.
-    Parent { left: NonNull<BlockNode<'proto_alloc, B, BLOCK_COUNT>>, right: NonNull<BlockNode<'proto_alloc, B, BLOCK_COUNT>> },
+    // The node represents a memory block that has been split in two buddies, stored as a pair in the node pool.
+    Parent { pair: usize },
 
     // The node represents an already allocated memory block.
-    AllocatedLeaf
+    AllocatedLeaf,
+
+    // The node represents a memory block that is permanently allocated and can never be freed.
+    Reserved
 
 }
 
 
 /// Node of the allocation tree.
 /// Each node is associated with a memory block.
-pub struct BlockNode<'proto_alloc, const B: usize, const BLOCK_COUNT: usize> {
+#[derive(Clone, Copy)]
+pub struct BlockNode {
 
-    /// Start address of the associated memory block
-    pub(super) block_address: NonNull<u8>,
+    /// Offset of the associated memory block from the start of the heap.
+    block_offset: usize,
 
     /// Size of the associated memory block in bytes.
     size: usize,
 
     /// State of the associated memory block (free, allocated, split).
-    state: BlockState<'proto_alloc, B, BLOCK_COUNT>,
-
-    _phantom_proto_allocator: PhantomData<Pin<&'proto_alloc mut FixedSizeAllocator<{block_node_size!()}, BLOCK_COUNT>>>
+    state: BlockState,
 
 }
 
-impl<'proto_alloc, const B: usize, const BLOCK_COUNT: usize> BlockNode<'proto_alloc, B, BLOCK_COUNT> {
-
-    // The compiler cannot recognize the type is indeed used
-    #[allow(dead_code)]
-    pub type ProtoAllocator = Pin<&'proto_alloc mut FixedSizeAllocator<{block_node_size!()}, BLOCK_COUNT>>;
+impl BlockNode {
 
     /// Create a new free leaf node.
-    pub fn new(size: usize, address: NonNull<u8>) -> Self {
+    pub const fn new(size: usize, offset: usize) -> Self {
         Self {
-            block_address: address,
+            block_offset: offset,
             size,
-            state: BlockState::FreeLeaf,
-            _phantom_proto_allocator: Default::default()
+            state: BlockState::FreeLeaf
         }
-    } 
+    }
 
+}
 
-    /// Create a new node and propagate the allocation.
-    /// Assume `alloc_size` <= `block_size`
-    fn new_alloc(block_size: usize, address: NonNull<u8>, alloc_size: usize, proto_allocator: &mut Self::ProtoAllocator) -> (Self, usize) {
-        
-        let (state, allocated) =  Self::alloc_down(address, block_size, alloc_size, proto_allocator);
 
-        (
-            Self {
-                block_address: address,
-                size: block_size,
-                state,
-                _phantom_proto_allocator: Default::default()
-            },
-            allocated
-        )
+/// Location of a node in the allocation tree.
+/// `0` is the root, while `2 * i + 1` and `2 * i + 2` are the children stored in the pair at index `i` of the node pool.
+#[derive(Clone, Copy)]
+struct NodeId(usize);
+
+impl NodeId {
+
+    const ROOT: Self = Self(0);
+
+    const fn child(pair: usize, side: usize) -> Self {
+        Self(2 * pair + 1 + side)
     }
 
+}
+
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+
+/// Upper bound on the depth of any allocation tree, since every level halves the block size.
+/// Iterative traversals keep at most this many node ids on the stack.
+const MAX_DEPTH: usize = usize::BITS as usize;
+
+
+/**
+    Fixed-capacity storage for the non-root nodes of the allocation tree.
+
+    Buddies are always split and merged together, so nodes are stored in pairs of siblings.
+    A tree over `N` zero-order blocks has at most `N` leaves and thus at most `N - 1` parents, so `N` pairs are always enough.
+    Pairs are referenced by index, which keeps the tree valid wherever the pool is moved.
+*/
+struct NodePool<const N: usize> {
+
+    /// Storage for the node pairs. Pairs at index `initialized` and above have never been used.
+    pairs: [MaybeUninit<[BlockNode; 2]>; N],
 
-    /// Recursively propagate the allocation down to the smallest memory block that can fit the requested size.
-    fn alloc_down(block_address: NonNull<u8>, block_size: usize, alloc_size: usize, proto_allocator: &mut Self::ProtoAllocator) -> (BlockState<'proto_alloc, B, BLOCK_COUNT>, usize) {
+    /// Number of pairs that have been used at least once.
+    initialized: usize,
 
-        let half_size = block_size / 2;
+    /// Stack of the indices of the pairs that have been released and can be reused. Only the first `released_count` entries are initialized.
+    released: [MaybeUninit<usize>; N],
 
-        // If the requested size is greater than half the block size, the block cannot be split.
-        // Also, the block cannot be split further if it's a zero-order block.
-        if alloc_size > half_size || block_size == B {
-            (BlockState::AllocatedLeaf, block_size)
+    /// Number of released pairs.
+    released_count: usize,
 
+}
+
+impl<const N: usize> NodePool<N> {
+
+    const fn new() -> Self {
+        Self {
+            pairs: [const { MaybeUninit::uninit() }; N],
+            initialized: 0,
+            released: [const { MaybeUninit::uninit() }; N],
+            released_count: 0
+        }
+    }
+
+
+    /// Initialize an empty pool at `this` without building it on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    unsafe fn init_in_place(this: *mut Self) {
+        // The storage arrays are allowed to be uninitialized
+        unsafe {
+            (&raw mut (*this).initialized).write(0);
+            (&raw mut (*this).released_count).write(0);
+        }
+    }
+
+
+    /// Store a new pair of nodes and return its index.
+    fn alloc(&mut self, pair: [BlockNode; 2]) -> usize {
+
+        let index = if self.released_count > 0 {
+            self.released_count -= 1;
+            unsafe { self.released[self.released_count].assume_init() }
         } else {
-            // Split the block in two identical buddy blocks and propagate the allocation.
+            // The capacity of the pool is never exceeded by a valid tree
+            assert!(self.initialized < N, "The allocation tree node pool is exhausted");
+            self.initialized += 1;
+            self.initialized - 1
+        };
+
+        self.pairs[index].write(pair);
+        index
+    }
 
-            let (left, allocated) = BlockNode::<B, BLOCK_COUNT>::new_alloc(half_size, block_address, alloc_size, proto_allocator);
 
-            unsafe {
+    /// Release the pair at `index` so that it can be reused.
+    fn release(&mut self, index: usize) {
+        self.released[self.released_count].write(index);
+        self.released_count += 1;
+    }
 
-                let left_ptr: NonNull<BlockNode<B, BLOCK_COUNT>> = mem::transmute(proto_allocator.as_mut().alloc_untyped().unwrap());
-                left_ptr.write(left);
 
-                let right_ptr: NonNull<BlockNode<B, BLOCK_COUNT>> = mem::transmute(proto_allocator.as_mut().alloc_untyped().unwrap());
-                right_ptr.write(
-                    BlockNode::new(half_size, NonNull::new_unchecked(block_address.as_ptr().byte_add(half_size)))
-                );
+    /// Return the pair at `index`.
+    /// Assume the pair has been allocated.
+    fn get(&self, index: usize) -> &[BlockNode; 2] {
+        debug_assert!(index < self.initialized);
+        unsafe { self.pairs[index].assume_init_ref() }
+    }
 
-                (
-                    BlockState::Parent {
-                        left: left_ptr,
-                        right: right_ptr
-                    },
-                    allocated
-                )
+
+    /// Return the pair at `index`.
+    /// Assume the pair has been allocated.
+    fn get_mut(&mut self, index: usize) -> &mut [BlockNode; 2] {
+        debug_assert!(index < self.initialized);
+        unsafe { self.pairs[index].assume_init_mut() }
+    }
+
+
+    /// Return the number of pairs currently in use.
+    const fn in_use(&self) -> usize {
+        self.initialized - self.released_count
+    }
+
+
+    /// Release every pair.
+    fn clear(&mut self) {
+        self.initialized = 0;
+        self.released_count = 0;
+    }
+
+}
+
+
+/// A binary tree that keeps track of the allocated and free blocks of a heap split into `N` zero-order blocks of `B` bytes.
+/// The tree doesn't depend on the address of the heap, nor on its own address.
+/// Allocations and frees walk the tree iteratively, so their stack usage is bounded and doesn't depend on the heap geometry.
+pub struct AllocTable<const B: usize, const N: usize> {
+
+    /// The node associated with the whole heap.
+    root: BlockNode,
+
+    /// Storage for all the other nodes.
+    pool: NodePool<N>,
+
+    /// When free buddies are merged.
+    policy: CoalescingPolicy,
+
+    /// Number of parents whose children are both free, because their merge has been deferred.
+    mergeable: usize,
+
+    stats: CoalescingStats,
+
+    /// Size of the largest block that may be free as a whole. Larger blocks are kept split.
+    split_limit: usize,
+
+    /// Total size of the reserved blocks.
+    reserved: usize,
+
+}
+
+impl<const B: usize, const N: usize> AllocTable<B, N> {
+
+    /// Create a new table where the whole heap is free.
+    pub const fn new() -> Self {
+        Self {
+            // The root block spans the whole heap, starting at offset 0
+            root: BlockNode::new(B * N, 0),
+            pool: NodePool::new(),
+            policy: CoalescingPolicy::Eager,
+            mergeable: 0,
+            stats: CoalescingStats::new(),
+            split_limit: B * N,
+            reserved: 0
+        }
+    }
+
+
+    /// Initialize a table where the whole heap is free at `this`, without building it on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    pub unsafe fn init_in_place(this: *mut Self) {
+        unsafe {
+            (&raw mut (*this).root).write(BlockNode::new(B * N, 0));
+            NodePool::init_in_place(&raw mut (*this).pool);
+            (&raw mut (*this).policy).write(CoalescingPolicy::Eager);
+            (&raw mut (*this).mergeable).write(0);
+            (&raw mut (*this).stats).write(CoalescingStats::new());
+            (&raw mut (*this).split_limit).write(B * N);
+            (&raw mut (*this).reserved).write(0);
+        }
+    }
+
+
+    /// Mark the whole heap as free.
+    pub fn clear(&mut self) {
+        self.reset_root();
+        self.split_above_limit(NodeId::ROOT);
+    }
+
+
+    /// Turn the tree back into a single free root, regardless of the split limit.
+    fn reset_root(&mut self) {
+        self.root = BlockNode::new(B * N, 0);
+        self.pool.clear();
+        self.mergeable = 0;
+        self.reserved = 0;
+    }
+
+
+    /// Mark the whole heap as free, except for the reserved blocks.
+    pub fn clear_allocations(&mut self) {
+
+        let mut reserved = Vec::new();
+        self.for_each_leaf_in(NodeId::ROOT, &mut |node| {
+            if matches!(node.state, BlockState::Reserved) {
+                reserved.push((node.block_offset, node.size));
             }
+        });
+
+        self.clear();
+
+        // The reserved blocks were disjoint, so they can all be claimed again in the fresh tree
+        for (offset, size) in reserved {
+            self.claim_as(offset, size, BlockState::Reserved);
+            self.reserved += size;
         }
     }
 
 
-    /// Recursively try to allocate the requested size.
-    pub fn alloc(&mut self, alloc_size: usize, proto_allocator: &mut Self::ProtoAllocator) -> Option<(NonNull<u8>, usize)> {
-        
-        match self.state {
+    /// Return the total size of the reserved blocks.
+    pub const fn reserved(&self) -> usize {
+        self.reserved
+    }
 
-            BlockState::FreeLeaf => {
 
-                if self.size < alloc_size {
-                    // The block is too small for the requested size.
-                    None
+    /// Return the size of the largest block that could be free once every allocated block is freed.
+    /// Reserved blocks are never freed and blocks larger than the split limit are kept split, so this may be smaller than the heap.
+    pub fn largest_possible_block(&self) -> usize {
 
-                } else {
+        let largest = if self.reserved == 0 {
+            B * N
+        } else {
+            self.largest_unreserved_in(NodeId::ROOT).0
+        };
+
+        largest.min(self.split_limit)
+    }
 
-                    // If the block is big enough for the requested size, propagate the allocation.
-                    let (state, allocated) = Self::alloc_down(self.block_address, self.size, alloc_size, proto_allocator);
-                    self.state = state;
 
-                    // Whether it's the whole block or the first child, they share the base address
-                    Some((self.block_address, allocated))
+    /// Return the size of the largest block in the subtree rooted at `id` that contains no reserved block, and whether it's the whole subtree.
+    fn largest_unreserved_in(&self, id: NodeId) -> (usize, bool) {
+
+        let node = self.node(id);
+
+        match node.state {
+
+            BlockState::Parent { pair } => {
+                let (left, left_whole) = self.largest_unreserved_in(NodeId::child(pair, LEFT));
+                let (right, right_whole) = self.largest_unreserved_in(NodeId::child(pair, RIGHT));
+
+                if left_whole && right_whole {
+                    (node.size, true)
+                } else {
+                    (left.max(right), false)
                 }
             },
 
-            BlockState::Parent { mut left, mut right } => {
+            BlockState::Reserved => (0, false),
 
-                if self.size <= alloc_size {
-                    // The requested allocation will never fit in any of the children since a child is always smaller than a parent.
-                    // Stop the search here to avoid useless recursion.
-                    None
+            _ => (node.size, true)
+        }
+    }
+
+
+    /// Keep every block larger than `limit` bytes split, splitting the free ones right away.
+    /// Assume `limit` is a power of two no smaller than `B` and no larger than the heap.
+    pub fn set_split_limit(&mut self, limit: usize) {
+
+        // Merge everything the larger of both limits allows, so that no deferred merge is left uncounted, then split what the new limit doesn't allow
+        self.split_limit = self.split_limit.max(limit);
+        self.merge_all();
+
+        self.split_limit = limit;
+        self.split_above_limit(NodeId::ROOT);
+    }
+
+
+    /// Split every free block larger than the split limit in the subtree rooted at `id`.
+    fn split_above_limit(&mut self, id: NodeId) {
+
+        // Each popped node pushes its two children, so the stack holds at most one pending sibling per level, plus the current node's children
+        let mut stack = [id; MAX_DEPTH + 1];
+        let mut len = 1;
+
+        while len > 0 {
+            len -= 1;
+            let id = stack[len];
+            let node = *self.node(id);
+
+            if node.size <= self.split_limit {
+                continue;
+            }
+
+            match node.state {
+                BlockState::FreeLeaf => self.split(id),
+                BlockState::Parent { .. } => (),
+                BlockState::AllocatedLeaf | BlockState::Reserved => continue
+            }
+
+            let BlockState::Parent { pair } = self.node(id).state else { unreachable!() };
+            stack[len] = NodeId::child(pair, RIGHT);
+            stack[len + 1] = NodeId::child(pair, LEFT);
+            len += 2;
+        }
+    }
+
+
+    pub const fn policy(&self) -> CoalescingPolicy {
+        self.policy
+    }
+
+
+    /// Change the coalescing policy, merging the deferred pairs of buddies the new policy doesn't allow to keep.
+    pub fn set_policy(&mut self, policy: CoalescingPolicy) {
+
+        self.policy = policy;
+
+        match policy {
+            CoalescingPolicy::Eager => self.coalesce(),
+            CoalescingPolicy::Watermark(watermark) if self.mergeable > watermark => self.coalesce(),
+            _ => ()
+        }
+    }
+
+
+    pub const fn stats(&self) -> CoalescingStats {
+        self.stats
+    }
+
+
+    fn node(&self, id: NodeId) -> &BlockNode {
+        match id.0 {
+            0 => &self.root,
+            child => &self.pool.get((child - 1) / 2)[(child - 1) % 2]
+        }
+    }
+
+
+    fn node_mut(&mut self, id: NodeId) -> &mut BlockNode {
+        match id.0 {
+            0 => &mut self.root,
+            child => &mut self.pool.get_mut((child - 1) / 2)[(child - 1) % 2]
+        }
+    }
+
+
+    /// Return the child of the node split into `pair` that contains `offset`.
+    fn child_containing(&self, pair: usize, offset: usize) -> NodeId {
+        let side = if offset < self.pool.get(pair)[RIGHT].block_offset { LEFT } else { RIGHT };
+        NodeId::child(pair, side)
+    }
+
+
+    /// Return whether both children of the node split into `pair` are free and the split limit allows merging them, which means they should be merged.
+    fn can_merge(&self, pair: usize) -> bool {
+        let children = self.pool.get(pair);
+        children[LEFT].size * 2 <= self.split_limit
+            && matches!(children.each_ref().map(|child| child.state), [BlockState::FreeLeaf, BlockState::FreeLeaf])
+    }
+
+
+    /// Split a free block in two free buddy blocks.
+    fn split(&mut self, id: NodeId) {
+
+        let node = *self.node(id);
+        let half_size = node.size / 2;
+
+        let pair = self.pool.alloc([
+            BlockNode::new(half_size, node.block_offset),
+            BlockNode::new(half_size, node.block_offset + half_size)
+        ]);
+
+        self.node_mut(id).state = BlockState::Parent { pair };
+        self.stats.splits += 1;
+    }
+
+
+    /// Account for the free leaf `id` being allocated or split.
+    /// If its buddy is free as well, their merge had been deferred and now never needs to happen.
+    fn reuse_free_leaf(&mut self, id: NodeId) {
+
+        if id.0 == 0 {
+            return;
+        }
+
+        let pair = (id.0 - 1) / 2;
+        if self.can_merge(pair) {
+            self.mergeable -= 1;
+            self.stats.merges_saved += 1;
+        }
+    }
+
+
+    /// Merge every pair of free buddies whose merge has been deferred, along with the merges they enable up the tree.
+    pub fn coalesce(&mut self) {
+        if self.mergeable > 0 {
+            self.merge_all();
+        }
+    }
+
+
+    /// Merge every pair of free buddies in the tree, whether or not their merge has been counted as deferred.
+    fn merge_all(&mut self) {
+
+        // Post-order traversal of the tree, where each node is paired with whether its children have already been visited.
+        // The stack holds the nodes on the current path and the right siblings of their children, so it fits in twice `MAX_DEPTH`.
+        let mut stack = [(NodeId::ROOT, false); 2 * MAX_DEPTH + 1];
+        let mut len = 1;
+
+        while len > 0 {
+            len -= 1;
+            let (id, visited) = stack[len];
+
+            let BlockState::Parent { pair } = self.node(id).state else { continue };
+
+            if !visited {
+                stack[len] = (id, true);
+                stack[len + 1] = (NodeId::child(pair, RIGHT), false);
+                stack[len + 2] = (NodeId::child(pair, LEFT), false);
+                len += 3;
+
+            } else if self.can_merge(pair) {
+                self.node_mut(id).state = BlockState::FreeLeaf;
+                self.pool.release(pair);
+                self.stats.merges += 1;
+            }
+        }
+
+        self.mergeable = 0;
+    }
+
+
+    /// Propagate the allocation down to the smallest memory block that can fit the requested size, always splitting toward the given side.
+    /// Return the offset of the allocated block and the amount of memory actually allocated.
+    /// Assume the block is a free leaf and `alloc_size` <= its size.
+    fn alloc_down(&mut self, mut id: NodeId, alloc_size: usize, side: usize) -> (usize, usize) {
+
+        loop {
+            let node = *self.node(id);
+
+            // If the requested size is greater than half the block size, the block cannot be split.
+            // Also, the block cannot be split further if it's a zero-order block.
+            if alloc_size > node.size / 2 || node.size == B {
+                self.node_mut(id).state = BlockState::AllocatedLeaf;
+                return (node.block_offset, node.size);
+            }
+
+            // Split the block in two identical buddy blocks and propagate the allocation to the one on the preferred side.
+            self.split(id);
+
+            let BlockState::Parent { pair } = self.node(id).state else { unreachable!() };
+            id = NodeId::child(pair, side);
+        }
+    }
+
+
+    /// Try to allocate the requested size toward the low end of the heap.
+    /// Return the offset of the allocated block and the amount of memory actually allocated.
+    pub fn alloc(&mut self, alloc_size: usize) -> Option<(usize, usize)> {
+        self.alloc_placed(alloc_size, Placement::LowAddresses)
+    }
+
+
+    /// Try to allocate the requested size toward the end of the heap chosen by `placement`.
+    /// Return the offset of the allocated block and the amount of memory actually allocated.
+    pub fn alloc_placed(&mut self, alloc_size: usize, placement: Placement) -> Option<(usize, usize)> {
+
+        self.alloc_first_fit(alloc_size, placement).or_else(|| {
+            // The deferred merges may make room for the allocation
+            if self.mergeable > 0 {
+                self.coalesce();
+                self.alloc_first_fit(alloc_size, placement)
+            } else {
+                None
+            }
+        })
+    }
+
+
+    /// Try to allocate the requested size in the first free block that fits it, in address order or in reverse address order.
+    fn alloc_first_fit(&mut self, alloc_size: usize, placement: Placement) -> Option<(usize, usize)> {
+
+        let (first, second) = match placement {
+            Placement::LowAddresses => (LEFT, RIGHT),
+            Placement::HighAddresses => (RIGHT, LEFT)
+        };
+
+        // Children yet to be searched, deepest last. Each is the sibling of a node on the current path, so they fit in `MAX_DEPTH`.
+        let mut pending = [NodeId::ROOT; MAX_DEPTH];
+        let mut pending_count = 0;
+
+        let mut id = NodeId::ROOT;
+
+        loop {
+            let node = *self.node(id);
+
+            match node.state {
+
+                // If the block is big enough for the requested size, propagate the allocation.
+                BlockState::FreeLeaf if node.size >= alloc_size => {
+                    self.reuse_free_leaf(id);
+                    return Some(self.alloc_down(id, alloc_size, first));
+                },
+
+                // The requested allocation may only fit in any of the children if they are bigger than it.
+                // Since a child is always smaller than a parent, this avoids useless searches.
+                BlockState::Parent { pair } if node.size > alloc_size => {
+                    pending[pending_count] = NodeId::child(pair, second);
+                    pending_count += 1;
+                    id = NodeId::child(pair, first);
+                    continue;
+                },
+
+                // The block is allocated or too small for the requested size.
+                _ => ()
+            }
+
+            if pending_count == 0 {
+                return None;
+            }
+            pending_count -= 1;
+            id = pending[pending_count];
+        }
+    }
+
+
+    /// Mark the block of `claim_size` bytes that starts at `offset` as allocated, splitting free blocks as needed.
+    /// Return whether the block could be claimed, that is, whether no part of it was already allocated.
+    /// Assume `claim_size` is a power of two no smaller than `B` and no larger than the heap, and that `offset` is within the heap and aligned to `claim_size`.
+    pub fn claim(&mut self, offset: usize, claim_size: usize) -> bool {
+        self.claim_as(offset, claim_size, BlockState::AllocatedLeaf)
+    }
+
+
+    /// Claim the block of `claim_size` bytes that starts at `offset` like `claim()`, turning it into a leaf of the given state.
+    fn claim_as(&mut self, offset: usize, claim_size: usize, state: BlockState) -> bool {
+
+        let mut id = NodeId::ROOT;
+
+        // Whether a block has been split on the way, so that the next blocks are fresh buddies
+        let mut split = false;
+
+        loop {
+            let node = *self.node(id);
+            let free = matches!(node.state, BlockState::FreeLeaf);
+
+            if free && !split {
+                self.reuse_free_leaf(id);
+            }
+
+            if node.size == claim_size {
+
+                // This is the requested block. It can only be claimed as a whole.
+                if free {
+                    self.node_mut(id).state = state;
                 }
-                // Check if any of the children can allocate the requested memory
-                else if let Some(ptr) = unsafe { left.as_mut() }.alloc(alloc_size, proto_allocator) {
-                    Some(ptr)
-                } else if let Some(ptr) = unsafe { right.as_mut() }.alloc(alloc_size, proto_allocator) {
-                    Some(ptr)
-                } else {
-                    None
+                return free;
+            }
+
+            if free {
+                // Split the block in two free buddies to reach the requested block.
+                self.split(id);
+                split = true;
+            }
+
+            match self.node(id).state {
+
+                BlockState::Parent { pair } => id = self.child_containing(pair, offset),
+
+                // A larger block containing the requested one is already allocated.
+                _ => return false
+            }
+        }
+    }
+
+
+    /// Return whether the block of `claim_size` bytes that starts at `offset` could be claimed without changing the tree.
+    /// Assume no merge is pending, so that a split block always contains an allocated block.
+    fn can_claim(&self, offset: usize, claim_size: usize) -> bool {
+
+        let mut id = NodeId::ROOT;
+
+        loop {
+            let node = self.node(id);
+
+            match node.state {
+                BlockState::FreeLeaf => return true,
+                BlockState::Parent { pair } if node.size > claim_size => id = self.child_containing(pair, offset),
+                _ => return false
+            }
+        }
+    }
+
+
+    /// Mark every block in the range from `start` to `end` as reserved, splitting free blocks as needed.
+    /// Return whether the range could be reserved, that is, whether no part of it was allocated. The tree is left untouched otherwise.
+    /// Assume `start` and `end` are multiples of `B` within the heap.
+    pub fn reserve_range(&mut self, start: usize, end: usize) -> bool {
+
+        // Merge the deferred buddies first, so that free blocks are never split
+        self.coalesce();
+
+        if !Self::covering_blocks(start, end, self.split_limit).all(|(offset, size)| self.can_claim(offset, size)) {
+            return false;
+        }
+
+        for (offset, size) in Self::covering_blocks(start, end, self.split_limit) {
+            self.claim_as(offset, size, BlockState::Reserved);
+            self.reserved += size;
+        }
+
+        true
+    }
+
+
+    /// Return the largest aligned blocks no larger than `limit` that exactly cover the range from `start` to `end`, in address order.
+    fn covering_blocks(start: usize, end: usize, limit: usize) -> impl Iterator<Item = (usize, usize)> {
+
+        let mut offset = start;
+
+        std::iter::from_fn(move || {
+
+            if offset >= end {
+                return None;
+            }
+
+            // A block must be aligned to its size and fit in the rest of the range
+            let alignment = if offset == 0 { limit } else { 1 << offset.trailing_zeros() };
+            let fitting = 1 << (usize::BITS - 1 - (end - offset).leading_zeros());
+            let size = alignment.min(fitting).min(limit);
+
+            offset += size;
+            Some((offset - size, size))
+        })
+    }
+
+
+    /// Try to free the block at the given offset.
+    /// Return the size of the freed block.
+    pub fn free(&mut self, offset: usize) -> Result<usize, FreeError> {
+
+        // Ancestors of the freed block, from the root down
+        let mut path = [NodeId::ROOT; MAX_DEPTH];
+        let mut depth = 0;
+
+        let mut id = NodeId::ROOT;
+
+        let freed = loop {
+            let node = *self.node(id);
+
+            match node.state {
+
+                // Cannot free a free block.
+                BlockState::FreeLeaf => return Err(FreeError::DoubleFree),
+
+                // Descend into the node that contains the given offset.
+                BlockState::Parent { pair } => {
+                    path[depth] = id;
+                    depth += 1;
+                    id = self.child_containing(pair, offset);
+                },
+
+                // Only allow freeing the block if the given offset matches the block's start offset.
+                BlockState::AllocatedLeaf if node.block_offset == offset => {
+                    self.node_mut(id).state = BlockState::FreeLeaf;
+                    break node.size;
+                },
+
+                BlockState::AllocatedLeaf => return Err(FreeError::UnalignedFree),
+
+                // Reserved blocks can never be freed.
+                BlockState::Reserved => return Err(FreeError::ReservedBlock),
+            }
+        };
+
+        if freed > self.split_limit {
+            // The block was allocated before the split limit was lowered, so it's split as soon as it's freed
+            self.split_above_limit(id);
+            return Ok(freed);
+        }
+
+        if self.policy != CoalescingPolicy::Eager {
+
+            if depth > 0 {
+                let BlockState::Parent { pair } = self.node(path[depth - 1]).state else { unreachable!() };
+                if self.can_merge(pair) {
+                    self.mergeable += 1;
+                    self.stats.deferred_merges += 1;
                 }
+            }
+
+            if matches!(self.policy, CoalescingPolicy::Watermark(watermark) if self.mergeable > watermark) {
+                self.coalesce();
+            }
+
+            return Ok(freed);
+        }
+
+        // While both children of a node are free, merge them into a single block to avoid fragmentation.
+        while depth > 0 {
+            depth -= 1;
+            let parent = path[depth];
+
+            let BlockState::Parent { pair } = self.node(parent).state else { unreachable!() };
+            if !self.can_merge(pair) {
+                break;
+            }
+
+            self.node_mut(parent).state = BlockState::FreeLeaf;
+            self.pool.release(pair);
+            self.stats.merges += 1;
+        }
+
+        Ok(freed)
+    }
+
+
+    /// Return the size of the allocated block that starts at the given offset, without freeing it.
+    /// Fail with the same errors `free()` would return.
+    pub fn allocated_size(&self, offset: usize) -> Result<usize, FreeError> {
+
+        let mut id = NodeId::ROOT;
+
+        loop {
+            let node = self.node(id);
+
+            match node.state {
+
+                BlockState::FreeLeaf => return Err(FreeError::DoubleFree),
+
+                BlockState::Parent { pair } => id = self.child_containing(pair, offset),
+
+                BlockState::AllocatedLeaf => {
+                    return if node.block_offset == offset {
+                        Ok(node.size)
+                    } else {
+                        Err(FreeError::UnalignedFree)
+                    };
+                },
+
+                BlockState::Reserved => return Err(FreeError::ReservedBlock),
+            }
+        }
+    }
+
+
+    /// Call `f` with the offset and size of every allocated block, in address order.
+    /// Reserved blocks are not included.
+    pub fn for_each_allocated(&self, f: &mut impl FnMut(usize, usize)) {
+        self.for_each_leaf_in(NodeId::ROOT, &mut |node| {
+            if matches!(node.state, BlockState::AllocatedLeaf) {
+                f(node.block_offset, node.size);
+            }
+        });
+    }
+
+
+    /// Call `f` with every leaf of the subtree rooted at `id`, in address order.
+    fn for_each_leaf_in(&self, id: NodeId, f: &mut impl FnMut(&BlockNode)) {
+
+        let node = self.node(id);
+
+        match node.state {
+
+            BlockState::Parent { pair } => {
+                self.for_each_leaf_in(NodeId::child(pair, LEFT), f);
+                self.for_each_leaf_in(NodeId::child(pair, RIGHT), f);
             },
 
-            BlockState::AllocatedLeaf => None,
+            _ => f(node),
         }
     }
 
 
-    /// Recursively try to free the given pointer.
-    pub fn free(&mut self, ptr: NonNull<u8>, proto_allocator: &mut Self::ProtoAllocator) -> Result<usize, FreeError> {
-        
-        match self.state {
+    /// Check that the tree is consistent and that every node pair in use is reachable from the root.
+    /// Return the total size of the free blocks.
+    pub fn check_integrity(&self) -> Result<usize, IntegrityError> {
 
-            // Cannot free a free block.
-            BlockState::FreeLeaf => Err(FreeError::DoubleFree),
+        let mut pairs = 0;
+        let mut free = 0;
+        self.check_integrity_in(NodeId::ROOT, 0, B * N, &mut pairs, &mut free)?;
 
-            BlockState::Parent { mut left, mut right } => {
+        if pairs != self.pool.in_use() {
+            return Err(IntegrityError::NodeCountMismatch { reachable: pairs * 2, allocated: self.pool.in_use() * 2 });
+        }
 
-                let left_ref = unsafe { left.as_mut() };
-                let right_ref = unsafe { right.as_mut() };
+        Ok(free)
+    }
 
-                // Free the node that contains the given pointer.
-                let freed = if ptr < right_ref.block_address {
-                    left_ref.free(ptr, proto_allocator)?
-                } else {
-                    right_ref.free(ptr, proto_allocator)?
-                };
 
-                // If both children nodes are free, merge them into a single block to avoid fragmentation.
-                if matches!((&left_ref.state, &right_ref.state), (BlockState::FreeLeaf, BlockState::FreeLeaf)) {
+    /// Recursively check the subtree rooted at `id`, assuming its root should describe the block of `expected_size` bytes at `expected_offset`.
+    /// Count the node pairs of the subtree in `pairs` and add the size of its free blocks to `free`.
+    fn check_integrity_in(&self, id: NodeId, expected_offset: usize, expected_size: usize, pairs: &mut usize, free: &mut usize) -> Result<(), IntegrityError> {
+
+        let node = self.node(id);
+
+        if node.block_offset != expected_offset || node.size != expected_size {
+            return Err(IntegrityError::BadTiling { expected_offset, expected_size, offset: node.block_offset, size: node.size });
+        }
+
+        match node.state {
+
+            BlockState::FreeLeaf => {
+                *free += node.size;
+                Ok(())
+            },
+
+            BlockState::Parent { pair } => {
+
+                if node.size == B {
+                    return Err(IntegrityError::SplitZeroOrderBlock { offset: node.block_offset });
+                }
 
-                    self.state = BlockState::FreeLeaf;
+                if pair >= self.pool.initialized {
+                    return Err(IntegrityError::ForeignNode { offset: node.block_offset, size: node.size });
+                }
 
-                    // Free the children blockk
-                    proto_allocator.as_mut().free_nonnull(left).unwrap();
-                    proto_allocator.as_mut().free_nonnull(right).unwrap();
+                // Merges may only be deferred by a lazy coalescing policy
+                if self.policy == CoalescingPolicy::Eager && self.can_merge(pair) {
+                    return Err(IntegrityError::UnmergedBuddies { offset: node.block_offset, size: node.size });
                 }
 
-                Ok(freed)
+                *pairs += 1;
+
+                let half_size = node.size / 2;
+                self.check_integrity_in(NodeId::child(pair, LEFT), node.block_offset, half_size, pairs, free)?;
+                self.check_integrity_in(NodeId::child(pair, RIGHT), node.block_offset + half_size, half_size, pairs, free)
             },
 
-            BlockState::AllocatedLeaf => {
+            BlockState::AllocatedLeaf | BlockState::Reserved => Ok(()),
+        }
+    }
 
-                // Only allow freeing the block if the given pointer matches the block's start address.
-                if self.block_address == ptr {
-                    self.state = BlockState::FreeLeaf;
-                    Ok(self.size)
-                } else {
-                    Err(FreeError::UnalignedFree)
+
+    /// Append the pre-order encoding of the tree to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        self.encode_in(NodeId::ROOT, out);
+    }
+
+
+    fn encode_in(&self, id: NodeId, out: &mut Vec<u8>) {
+
+        match self.node(id).state {
+
+            BlockState::FreeLeaf => out.push(FREE_LEAF_TAG),
+
+            BlockState::Parent { pair } => {
+
+                let start = out.len();
+                out.push(PARENT_TAG);
+                self.encode_in(NodeId::child(pair, LEFT), out);
+                self.encode_in(NodeId::child(pair, RIGHT), out);
+
+                // Encode buddies whose merge has been deferred as the free block they would merge into
+                if out[start..] == [PARENT_TAG, FREE_LEAF_TAG, FREE_LEAF_TAG] {
+                    out.truncate(start);
+                    out.push(FREE_LEAF_TAG);
                 }
             },
+
+            BlockState::AllocatedLeaf => out.push(ALLOCATED_LEAF_TAG),
+
+            BlockState::Reserved => out.push(RESERVED_LEAF_TAG),
         }
     }
 
-}
 
+    /// Replace the tree with the one described by its pre-order encoding.
+    /// Assume the encoding has already been validated.
+    pub fn decode(&mut self, mut encoding: &[u8]) {
 
-// A crappy workaround to satisfy trait constraints
-static_assertions::const_assert_eq!(40, mem::size_of::<BlockNode<0, 0>>());
-#[macro_export]
-macro_rules! block_node_size {
-    () => {
-        40
-    };
-}
+        // Rebuilding the tree doesn't count as splitting blocks
+        let stats = self.stats;
+
+        self.reset_root();
+        self.decode_in(NodeId::ROOT, &mut encoding);
+        self.split_above_limit(NodeId::ROOT);
+
+        self.stats = stats;
+        if self.policy == CoalescingPolicy::Eager {
+            self.coalesce();
+        }
+    }
+
+
+    /// Recursively rebuild the subtree rooted at the free leaf `id`, consuming its encoding from `encoding`.
+    fn decode_in(&mut self, id: NodeId, encoding: &mut &[u8]) {
+
+        let (&tag, rest) = encoding.split_first().unwrap();
+        *encoding = rest;
+
+        match tag {
+
+            FREE_LEAF_TAG => (),
+
+            ALLOCATED_LEAF_TAG => self.node_mut(id).state = BlockState::AllocatedLeaf,
 
+            RESERVED_LEAF_TAG => {
+                self.node_mut(id).state = BlockState::Reserved;
+                self.reserved += self.node(id).size;
+            },
+
+            _ => {
+                self.split(id);
+
+                let BlockState::Parent { pair } = self.node(id).state else { unreachable!() };
+                self.decode_in(NodeId::child(pair, LEFT), encoding);
+                self.decode_in(NodeId::child(pair, RIGHT), encoding);
+
+                if self.can_merge(pair) {
+                    self.mergeable += 1;
+                }
+            }
+        }
+    }
+
+}
@@ -0,0 +1,180 @@
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::mem;
+use std::ptr::NonNull;
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{buddy_allocator::BuddyAllocator, errors::AllocError};
+
+
+/// Every chunk starts with the address of the previously allocated chunk, which may be unaligned.
+type ChunkLink = Option<NonNull<u8>>;
+
+const LINK_SIZE: usize = mem::size_of::<ChunkLink>();
+
+
+/**
+    A bump allocator whose memory is taken in chunks from a `BuddyAllocator`.
+
+    Allocations just move a pointer forward in the current chunk, and cannot be freed individually.
+    Instead, every chunk goes back to the allocator at once when the arena is reset or dropped,
+    which the borrow checker only allows once no allocation from the arena is alive anymore.
+
+    Values moved into the arena are never dropped.
+*/
+pub struct BuddyArena<'a, const M: usize, const B: usize>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// The allocator the chunks are taken from.
+    alloc: &'a BuddyAllocator<M, B>,
+
+    /// Size of a regular chunk in bytes. Larger allocations get a dedicated chunk.
+    chunk_size: usize,
+
+    /// The most recently allocated chunk, which links to the previous ones.
+    last_chunk: Cell<ChunkLink>,
+
+    /// Number of chunks currently held.
+    chunks: Cell<usize>,
+
+    /// Start of the unused part of the current chunk.
+    cursor: Cell<Option<NonNull<u8>>>,
+
+    /// Size of the unused part of the current chunk.
+    remaining: Cell<usize>,
+
+}
+
+impl<'a, const M: usize, const B: usize> BuddyArena<'a, M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Create an empty arena that takes chunks of `chunk_size` bytes from `alloc`.
+    /// The chunk size is rounded up to a power of two no smaller than `B`. No chunk is allocated until the first allocation.
+    pub fn new(alloc: &'a BuddyAllocator<M, B>, chunk_size: usize) -> Self {
+        Self {
+            alloc,
+            chunk_size: chunk_size.max(B).next_power_of_two(),
+            last_chunk: Cell::new(None),
+            chunks: Cell::new(0),
+            cursor: Cell::new(None),
+            remaining: Cell::new(0)
+        }
+    }
+
+
+    /// Return the size of a regular chunk in bytes.
+    pub const fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+
+    /// Return the number of chunks currently taken from the allocator.
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.get()
+    }
+
+
+    /// Move `value` into the arena and return a reference to it, which lives as long as the arena isn't reset.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> Result<&mut T, AllocError> {
+
+        let ptr = self.alloc_layout(Layout::new::<T>())?.cast::<T>();
+
+        // Every allocation from the arena is a distinct memory region
+        unsafe {
+            ptr.write(value);
+            Ok(&mut *ptr.as_ptr())
+        }
+    }
+
+
+    /// Allocate a memory region that fits `layout`.
+    /// Return a pointer to the start of the region, which stays valid as long as the arena isn't reset.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_layout(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+
+        if let Some(ptr) = self.bump(layout) {
+            return Ok(ptr);
+        }
+
+        // Make sure the new chunk fits the allocation even if its start is not aligned
+        let needed = LINK_SIZE + layout.align() - 1 + layout.size();
+        let size = if needed <= self.chunk_size { self.chunk_size } else { needed.next_power_of_two() };
+
+        let chunk = self.alloc.alloc_bytes(size)?;
+
+        unsafe {
+            chunk.cast::<ChunkLink>().write_unaligned(self.last_chunk.get());
+            self.cursor.set(Some(chunk.byte_add(LINK_SIZE)));
+        }
+        self.last_chunk.set(Some(chunk));
+        self.chunks.set(self.chunks.get() + 1);
+        self.remaining.set(size - LINK_SIZE);
+
+        // The chunk was sized for the allocation, so this cannot fail
+        Ok(self.bump(layout).unwrap())
+    }
+
+
+    /// Allocate a memory region that fits `layout` from the unused part of the current chunk, if it's big enough.
+    fn bump(&self, layout: Layout) -> Option<NonNull<u8>> {
+
+        let cursor = self.cursor.get()?;
+
+        let padding = cursor.align_offset(layout.align());
+        let used = padding.checked_add(layout.size())?;
+
+        if used > self.remaining.get() {
+            return None;
+        }
+
+        unsafe {
+            self.cursor.set(Some(cursor.byte_add(used)));
+            self.remaining.set(self.remaining.get() - used);
+            Some(cursor.byte_add(padding))
+        }
+    }
+
+
+    /// Return every chunk to the allocator, making room for new allocations.
+    pub fn reset(&mut self) {
+
+        while let Some(chunk) = self.last_chunk.get() {
+            self.last_chunk.set(unsafe { chunk.cast::<ChunkLink>().read_unaligned() });
+
+            // Chunks are allocated blocks of the allocator, so this cannot fail
+            let _ = self.alloc.free_nonnull(chunk);
+        }
+
+        self.chunks.set(0);
+        self.cursor.set(None);
+        self.remaining.set(0);
+    }
+
+}
+
+impl<const M: usize, const B: usize> Drop for BuddyArena<'_, M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    fn drop(&mut self) {
+        self.reset();
+    }
+
+}
@@ -1,16 +1,18 @@
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
+use std::slice;
 use std::pin::Pin;
 use std::mem::{self, MaybeUninit};
 use std::marker::PhantomPinned;
 use std::cell::UnsafeCell;
+use std::thread;
 
 use const_assert::{Assert, IsTrue};
-use fixed_size_allocator::FixedSizeAllocator;
 
-use crate::{alloc_table::BlockNode, block_node_size, errors::{AllocError, FreeError}};
-
-
-type ProtoAllocator<const N: usize> = FixedSizeAllocator<{block_node_size!()}, N>;
+#[cfg(feature = "debug-heap")]
+use crate::debug_heap::{self, Quarantine, QuarantinePolicy};
+#[cfg(feature = "track-allocations")]
+use crate::tracking::{AllocationInfo, Tracker};
+use crate::{alloc_table::AllocTable, coalescing::{CoalescingPolicy, CoalescingStats}, magazine::{MagazineConfig, Magazines}, placement::Placement, pressure::{OomAction, OomHandler, PressureHandler, PressureWatch}, errors::{AllocError, FreeError, IntegrityError, SnapshotError}, handle::BlockHandle, leaks::{Leak, LeakCheck}, snapshot, sub_allocator::SubAllocator};
 
 
 /**
@@ -18,98 +20,547 @@ type ProtoAllocator<const N: usize> = FixedSizeAllocator<{block_node_size!()}, N
 
     A zero-order block is the smallest possible memory block that can be allocated.
     Trying to allocate a memory block smaller than `B` will allocate a block of exactly `B` bytes.
-    
+
     Note that `B` and `M` must be integer powers of 2 such that `M = B * 2^n`, where `n` is a positive integer.
+
+    The allocator is used through shared references, so that several data structures can allocate from it at the same time.
+    It's meant to be used by a single thread, so it's not `Sync`.
 */
-pub struct BuddyAllocator<'a, const M: usize, const B: usize>
+pub struct BuddyAllocator<const M: usize, const B: usize>
 where 
     [(); M / B]:
 {
-    
+
     /// The actual buffer where the heap is stored.
-    memory: [MaybeUninit<u8>; M],
+    /// Allocated blocks are written through pointers handed out by the allocator, so the buffer is only ever accessed through raw pointers.
+    memory: UnsafeCell<[MaybeUninit<u8>; M]>,
 
-    /// A binary  tree that keeps track of the allocated and free blocks.
-    alloc_table: BlockNode<'a, B, {M / B}>,
+    /// The bookkeeping of the allocator, which is updated through shared references.
+    state: UnsafeCell<State<M, B>>,
 
-    /// Internal allocator used to allocate the `alloc_table` without relying on external allocators.
-    proto_allocator: UnsafeCell<ProtoAllocator<{M / B}>>,
-    /// Pin to the proto allocator
-    proto_allocator_pin: Pin<&'a mut ProtoAllocator<{M / B}>>,
+    /// Tell the compiler this struct should not be moved.
+    _pin: PhantomPinned
 
-    /// The highest address of the heap.
-    upper_memory_bound: NonNull<u8>,
+}
+
+
+/// The bookkeeping of a `BuddyAllocator`.
+struct State<const M: usize, const B: usize>
+where 
+    [(); M / B]:
+{
+
+    /// A binary tree that keeps track of the allocated and free blocks.
+    /// Its nodes are stored inline and reference each other by index, so no external allocator is needed.
+    alloc_table: AllocTable<B, {M / B}>,
 
     /// The total amount of free memory, which may not be available as a whole due to fragmentation.
     total_free: usize,
 
-    /// Tell the compiler this struct should not be moved.
-    _pin: PhantomPinned
+    /// Recently freed blocks of the smallest orders, which stay allocated in the allocation tree so that they can be handed out again quickly.
+    /// They count as free memory.
+    magazines: Magazines<B, {M / B}>,
+
+    /// Order of the largest block a single allocation may take, if capped.
+    max_alloc_order: Option<usize>,
+
+    /// Which end of the heap allocations are packed toward by default.
+    placement: Placement,
+
+    /// The size originally requested for each allocated block, indexed by zero-order block. `0` if unknown.
+    #[cfg(feature = "debug-heap")]
+    requested_sizes: [usize; M / B],
+
+    /// Freed blocks that are kept allocated and poisoned to detect use-after-free bugs.
+    #[cfg(feature = "debug-heap")]
+    quarantine: Quarantine<B, {M / B}>,
+
+    /// Call sites of the allocations and frees, indexed by zero-order block.
+    #[cfg(feature = "track-allocations")]
+    tracker: Tracker<{M / B}>,
+
+    /// What to do if some blocks are still allocated when the allocator is dropped.
+    leak_check: LeakCheck,
+
+    /// Called before an allocation fails for lack of memory. Taken out while it runs.
+    oom_handler: Option<OomHandler<BuddyAllocator<M, B>>>,
+
+    /// Incremented whenever the out-of-memory handler is set, so that a running handler isn't put back after replacing or removing itself.
+    oom_generation: u64,
+
+    /// Reports when the free memory crosses the watermarks, if configured.
+    pressure: Option<PressureWatch<BuddyAllocator<M, B>>>,
+
+    /// Incremented whenever the pressure handler is set, like `oom_generation`.
+    pressure_generation: u64,
 
 }
 
-impl<'a, const M: usize, const B: usize> BuddyAllocator<'a, M, B> 
+impl<const M: usize, const B: usize> State<M, B>
 where 
-    Assert<{ M.is_power_of_two() }>: IsTrue,
-    Assert<{ B.is_power_of_two() }>: IsTrue,
-    Assert<{ M % B == 0 }>: IsTrue,
     [(); M / B]:,
 {
 
-    // The compiler cannot recognize the type is indeed used
-    #[allow(dead_code)]
-    type PinnedProtoAllocator = Pin<&'a mut ProtoAllocator<{M / B}>>;
+    /// Create the bookkeeping of an empty heap.
+    const fn new() -> Self {
+        Self {
+            alloc_table: AllocTable::new(),
+            total_free: M,
+            magazines: Magazines::new(),
+            max_alloc_order: None,
+            placement: Placement::LowAddresses,
+            #[cfg(feature = "debug-heap")]
+            requested_sizes: [0; M / B],
+            #[cfg(feature = "debug-heap")]
+            quarantine: Quarantine::new(),
+            #[cfg(feature = "track-allocations")]
+            tracker: Tracker::new(),
+            leak_check: LeakCheck::Off,
+            oom_handler: None,
+            oom_generation: 0,
+            pressure: None,
+            pressure_generation: 0
+        }
+    }
 
 
-    pub unsafe fn new_unpinned(zero_initialized: bool) -> Self {
+    /// Initialize the bookkeeping of an empty heap at `this` without building it on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    unsafe fn init_in_place(this: *mut Self) {
+        unsafe {
+            AllocTable::init_in_place(&raw mut (*this).alloc_table);
+            (&raw mut (*this).total_free).write(M);
+            Magazines::init_in_place(&raw mut (*this).magazines);
+            (&raw mut (*this).max_alloc_order).write(None);
+            (&raw mut (*this).placement).write(Placement::LowAddresses);
+            #[cfg(feature = "debug-heap")]
+            {
+                (&raw mut (*this).requested_sizes).write_bytes(0, 1);
+                Quarantine::init_in_place(&raw mut (*this).quarantine);
+            }
+            #[cfg(feature = "track-allocations")]
+            Tracker::init_in_place(&raw mut (*this).tracker);
+            (&raw mut (*this).leak_check).write(LeakCheck::Off);
+            (&raw mut (*this).oom_handler).write(None);
+            (&raw mut (*this).oom_generation).write(0);
+            (&raw mut (*this).pressure).write(None);
+            (&raw mut (*this).pressure_generation).write(0);
+        }
+    }
 
-        let memory = if zero_initialized {
-            [MaybeUninit::<u8>::zeroed(); M]
+
+    /// Take a free block of at least `size` bytes, from its magazine if possible, and return its offset and size.
+    /// If the allocation tree has no room left, flush the magazines back into it and try again.
+    /// Blocks taken from the tree are placed toward the end of the heap chosen by `placement`, while cached blocks are reused wherever they are.
+    fn take_block(&mut self, size: usize, placement: Placement) -> Option<(usize, usize)> {
+
+        let order = Magazines::<B, {M / B}>::order_of(size);
+
+        if let Some(offset) = self.magazines.pop(order) {
+            return Some((offset, B << order));
+        }
+
+        self.alloc_table.alloc_placed(size, placement)
+            .or_else(|| {
+                if self.magazines.bytes() == 0 {
+                    return None;
+                }
+                self.flush_magazines();
+                self.alloc_table.alloc_placed(size, placement)
+            })
+    }
+
+
+    /// Account for the block of `allocated` bytes at `offset` being handed out for a request of `size` bytes.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    #[cfg_attr(not(feature = "debug-heap"), allow(unused_variables))]
+    fn record_alloc(&mut self, heap_base: NonNull<u8>, offset: usize, size: usize, allocated: usize) {
+
+        // Keep track of the free memory
+        self.total_free -= allocated;
+
+        #[cfg(feature = "debug-heap")]
+        {
+            self.requested_sizes[offset / B] = size;
+            unsafe {
+                debug_heap::poison_alloc(heap_base.byte_add(offset), size, allocated);
+            }
+        }
+
+        #[cfg(feature = "track-allocations")]
+        self.tracker.record_alloc(offset / B);
+    }
+
+
+    /// Move the allocated block that starts at `offset` into its magazine instead of freeing it.
+    /// Return the size of the cached block, or `None` if its order has no magazine or the magazine is full.
+    fn cache_block(&mut self, offset: usize) -> Result<Option<usize>, FreeError> {
+
+        if self.magazines.contains(offset) {
+            return Err(FreeError::DoubleFree);
+        }
+
+        let size = self.alloc_table.allocated_size(offset)?;
+
+        Ok(Some(size).filter(|&size| self.magazines.push(offset, size)))
+    }
+
+
+    /// Free every block cached in the magazines back into the allocation tree.
+    fn flush_magazines(&mut self) {
+        while let Some((offset, _)) = self.magazines.pop_any() {
+            // Cached blocks are still allocated in the allocation tree and already count as free memory, so this cannot fail
+            self.alloc_table.free(offset).unwrap();
+        }
+    }
+
+
+    /// Check the red zone of a block that is being freed and fill it with the free pattern.
+    /// Return whether the red zone was overwritten.
+    #[cfg(feature = "debug-heap")]
+    fn poison_freed_block(&mut self, heap_base: NonNull<u8>, offset: usize, size: usize) -> bool {
+
+        let block = unsafe { heap_base.byte_add(offset) };
+        let requested = mem::replace(&mut self.requested_sizes[offset / B], 0);
+
+        // Blocks whose requested size is unknown, for example because they were restored from a snapshot, have no red zone
+        let overflowed = requested != 0 && !unsafe { debug_heap::check_red_zone(block, requested, size) };
+
+        unsafe {
+            debug_heap::poison_free(block, size);
+        }
+
+        overflowed
+    }
+
+
+    /// Poison the allocated block referenced by `handle` and move it into the quarantine instead of freeing it.
+    /// Then release the blocks the quarantine policy no longer allows to hold.
+    #[cfg(feature = "debug-heap")]
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn quarantine_block(&mut self, heap_base: NonNull<u8>, handle: BlockHandle) -> Result<(), FreeError> {
+
+        if self.quarantine.contains(handle.offset()) || self.magazines.contains(handle.offset()) {
+            return Err(FreeError::DoubleFree);
+        }
+
+        let size = self.alloc_table.allocated_size(handle.offset())?;
+        let overflowed = self.poison_freed_block(heap_base, handle.offset(), size);
+
+        self.quarantine.push(handle.offset(), size);
+
+        #[cfg(feature = "track-allocations")]
+        self.tracker.record_free(handle.offset() / B);
+
+        let res = self.release_quarantined(heap_base, false);
+
+        if overflowed {
+            Err(FreeError::BufferOverflow)
         } else {
-            [MaybeUninit::<u8>::uninit(); M]
-        };
+            res
+        }
+    }
 
-        let res = Self {
-            memory,
-            #[allow(invalid_value)]
-            alloc_table: unsafe { MaybeUninit::uninit().assume_init() },
-            proto_allocator: UnsafeCell::new(unsafe { FixedSizeAllocator::<{block_node_size!()}, {M / B}>::new_unpinned(false) }),
-            proto_allocator_pin: unsafe { Pin::new_unchecked(mem::transmute(NonNull::<Self::PinnedProtoAllocator>::dangling())) },
-            upper_memory_bound: NonNull::dangling(),
-            total_free: M,
-            _pin: PhantomPinned::default()
-        };
+
+    /// Free the quarantined blocks the quarantine policy no longer allows to hold, or all of them if `all` is set.
+    /// Fail with `FreeError::UseAfterFree` if any released block was written after being freed. Every block is released regardless.
+    #[cfg(feature = "debug-heap")]
+    fn release_quarantined(&mut self, heap_base: NonNull<u8>, all: bool) -> Result<(), FreeError> {
+
+        let mut res = Ok(());
+
+        while let Some((offset, size)) = if all { self.quarantine.pop() } else { self.quarantine.pop_expired() } {
+
+            let intact = unsafe { debug_heap::check_free_poison(heap_base.byte_add(offset), size) };
+            if !intact && res.is_ok() {
+                res = Err(FreeError::UseAfterFree(BlockHandle::from_offset(offset)));
+            }
+
+            // Quarantined blocks are still allocated in the allocation tree, so this cannot fail
+            self.total_free += self.alloc_table.free(offset).unwrap();
+        }
 
         res
     }
 
+}
+
+impl<const M: usize, const B: usize> BuddyAllocator<M, B>
+where 
+    [(); M / B]:,
+{
+
+    /// Return the start address of the heap.
+    fn heap_base(&self) -> NonNull<u8> {
+        unsafe {
+            NonNull::new_unchecked(self.memory.get()).cast()
+        }
+    }
+
 
-    pub unsafe fn init_pinned(self: Pin<&mut Self>) {
+    /// Return the bookkeeping of the allocator for reading.
+    fn state(&self) -> &State<M, B> {
+        // Mutable references to the state never outlive the method that created them, and the allocator is not `Sync`
+        unsafe { &*self.state.get() }
+    }
 
-        let self_data = unsafe { self.get_unchecked_mut() };
-        
-        // Get the lower bound of the heap
-        let base_ptr = unsafe { 
-            NonNull::new_unchecked(self_data.memory.as_mut_ptr() as *mut u8)
-        };
 
-        // Initialize the allocation table
-        self_data.alloc_table = BlockNode::new(M, base_ptr);
+    /// Return the bookkeeping of the allocator for writing.
+    /// 
+    /// # Safety
+    /// 
+    /// The returned reference must not outlive the public method that requested it, which must not access the state in any other way meanwhile.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn state_mut(&self) -> &mut State<M, B> {
+        unsafe { &mut *self.state.get() }
+    }
+
+
+    /// Return whether an allocation of `size` bytes could succeed once every allocated block is freed.
+    /// This is not the case if reserved blocks or the split limit leave no free block large enough.
+    pub(crate) fn fits_when_empty(&self, size: usize) -> bool {
+        size <= M && size.div_ceil(B).next_power_of_two() * B <= self.state().alloc_table.largest_possible_block()
+    }
+
+
+    /// Return every block that is currently allocated, in address order.
+    /// Blocks held in the quarantine or cached in the magazines have already been freed, so they are not reported.
+    pub fn leak_report(&self) -> Vec<Leak> {
+
+        let state = self.state();
+        let mut leaks = Vec::new();
+
+        state.alloc_table.for_each_allocated(&mut |offset, size| {
+
+            if state.magazines.contains(offset) {
+                return;
+            }
+
+            #[cfg(feature = "debug-heap")]
+            if state.quarantine.contains(offset) {
+                return;
+            }
+
+            #[cfg(feature = "debug-heap")]
+            let requested_size = Some(state.requested_sizes[offset / B]).filter(|&requested| requested != 0);
+            #[cfg(not(feature = "debug-heap"))]
+            let requested_size = None;
+
+            leaks.push(Leak {
+                handle: BlockHandle::from_offset(offset),
+                address: unsafe { self.heap_base().byte_add(offset) },
+                size,
+                requested_size,
+                #[cfg(feature = "track-allocations")]
+                allocated_at: state.tracker.get(offset / B).map(|info| info.allocated_at.clone())
+            });
+        });
+
+        leaks
+    }
+
+
+    /// Return the call sites of the most recent allocation that started at `handle`, and of its free if it has been freed.
+    /// After a `FreeError::DoubleFree`, this tells who allocated the block and who freed it first.
+    #[cfg(feature = "track-allocations")]
+    pub fn allocation_info(&self, handle: BlockHandle) -> Option<AllocationInfo> {
+        if handle.offset() < M && handle.offset().is_multiple_of(B) {
+            self.state().tracker.get(handle.offset() / B).cloned()
+        } else {
+            None
+        }
+    }
+
+
+    /// Choose whether to capture a full backtrace of every allocation and free, in addition to its source location.
+    /// Backtrace capture is expensive, so it's off by default.
+    #[cfg(feature = "track-allocations")]
+    pub fn set_capture_backtraces(&self, capture_backtraces: bool) {
+        unsafe { self.state_mut() }.tracker.set_capture_backtraces(capture_backtraces);
+    }
+
+
+    /// Choose what to do if some blocks are still allocated when the allocator is dropped.
+    /// Leak checks are off by default.
+    pub fn set_leak_check(&self, leak_check: LeakCheck) {
+        unsafe { self.state_mut() }.leak_check = leak_check;
+    }
+
+
+    /// Choose when free buddy blocks are merged back into bigger blocks.
+    /// Coalescing is eager by default. Deferred pairs of buddies the new policy doesn't allow to keep are merged right away.
+    pub fn set_coalescing_policy(&self, policy: CoalescingPolicy) {
+        unsafe { self.state_mut() }.alloc_table.set_policy(policy);
+    }
+
+
+    /// Return the current coalescing policy.
+    pub fn coalescing_policy(&self) -> CoalescingPolicy {
+        self.state().alloc_table.policy()
+    }
+
+
+    /// Return how many blocks have been split and merged, and how many merges the coalescing policy has deferred or saved.
+    pub fn coalescing_stats(&self) -> CoalescingStats {
+        self.state().alloc_table.stats()
+    }
+
+
+    /// Merge every pair of free buddies whose merge has been deferred by the coalescing policy.
+    pub fn coalesce(&self) {
+        unsafe { self.state_mut() }.alloc_table.coalesce();
+    }
+
 
-        // Calculate the upper bound of the heap
-        self_data.upper_memory_bound = unsafe {
-            NonNull::new_unchecked(base_ptr.as_ptr().byte_add(M))
+    /// Configure the magazines, bounded caches of recently freed blocks of the smallest orders. Pass `None` to disable them.
+    /// Allocations of a cached order are served from its magazine without walking the allocation tree.
+    /// Cached blocks count as free memory. They are flushed back into the tree when an allocation cannot be satisfied otherwise, or through `trim()`.
+    /// Magazines are disabled by default. Changing the configuration flushes them.
+    pub fn set_magazines(&self, config: Option<MagazineConfig>) {
+
+        let state = unsafe { self.state_mut() };
+
+        state.flush_magazines();
+        state.magazines.set_config(config);
+    }
+
+
+    /// Cap the size of any single allocation to a block of order `max_order`, that is `B << max_order` bytes. Pass `None` to lift the cap.
+    /// Larger requests fail with `AllocError::ExceedsMaxOrder`, so that no single request can take the whole heap. Orders beyond the whole heap are clamped.
+    /// If `presplit` is set, the blocks larger than the cap are split right away and never merged back, so the heap stays divided into blocks of the maximum order.
+    /// Allocations are not capped by default.
+    pub fn set_max_alloc_order(&self, max_order: Option<usize>, presplit: bool) {
+
+        let state = unsafe { self.state_mut() };
+
+        let heap_order = (M / B).trailing_zeros() as usize;
+        state.max_alloc_order = max_order.map(|order| order.min(heap_order));
+
+        let split_limit = match state.max_alloc_order {
+            Some(order) if presplit => B << order,
+            _ => M
         };
+        state.alloc_table.set_split_limit(split_limit);
+    }
+
+
+    /// Return the order of the largest block a single allocation may take, if capped.
+    pub fn max_alloc_order(&self) -> Option<usize> {
+        self.state().max_alloc_order
+    }
+
+
+    /// Choose which end of the heap allocations are packed toward, unless a placement is given for the allocation itself.
+    /// Allocations are packed toward low addresses by default.
+    /// Blocks cached in the magazines are reused wherever they are.
+    pub fn set_placement(&self, placement: Placement) {
+        unsafe { self.state_mut() }.placement = placement;
+    }
+
+
+    /// Return the default placement of allocations.
+    pub fn placement(&self) -> Placement {
+        self.state().placement
+    }
+
+
+    /// Return the current configuration of the magazines.
+    pub fn magazines(&self) -> Option<MagazineConfig> {
+        self.state().magazines.config()
+    }
+
+
+    /// Flush every block cached in the magazines back into the allocation tree.
+    pub fn trim(&self) {
+        unsafe { self.state_mut() }.flush_magazines();
+    }
 
-        // Store a pin to the proto allocator
-        self_data.proto_allocator_pin = unsafe {
-            Pin::new_unchecked(self_data.proto_allocator.get().as_mut_unchecked())
+
+    /// Return the total size of the blocks cached in the magazines.
+    pub fn total_cached(&self) -> usize {
+        self.state().magazines.bytes()
+    }
+
+
+    /// Register a handler called with the requested size whenever an allocation is about to fail with `AllocError::OutOfMemory`.
+    /// The handler may free memory, like shrinking caches, and ask for a retry. It's called again if the retry fails as well.
+    /// Allocations made from within the handler don't call it again, but the handler may replace or remove itself. Pass `None` to remove the handler.
+    pub fn set_oom_handler(&self, handler: Option<OomHandler<Self>>) {
+
+        let state = unsafe { self.state_mut() };
+
+        state.oom_handler = handler;
+        state.oom_generation += 1;
+    }
+
+
+    /// Register a handler called when the free memory drops below `low` bytes, and when it rises back to `high` bytes afterwards.
+    /// Setting `high` above `low` keeps the handler from firing repeatedly while the free memory hovers around a single watermark.
+    /// The free memory is checked after every allocation, free and reservation, so changes caused by the handler itself are reported on the next one.
+    /// The handler may replace or remove itself. Pass `None` to stop watching the free memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `low` is greater than `high`.
+    pub fn set_pressure_handler(&self, watermarks: (usize, usize), handler: Option<PressureHandler<Self>>) {
+
+        let state = unsafe { self.state_mut() };
+
+        let (low, high) = watermarks;
+        state.pressure = handler.map(|handler| PressureWatch::new(low, high, state.total_free, handler));
+        state.pressure_generation += 1;
+    }
+
+
+    /// Return the low and high watermarks of the pressure handler, if any.
+    pub fn watermarks(&self) -> Option<(usize, usize)> {
+        self.state().pressure.as_ref().map(PressureWatch::watermarks)
+    }
+
+
+    /// Call the pressure handler if the free memory crossed a watermark since the last check.
+    /// The state is not borrowed while the handler runs, so that it can use the allocator.
+    fn check_pressure(&self) {
+
+        let state = unsafe { self.state_mut() };
+        let total_free = state.total_free;
+        let generation = state.pressure_generation;
+
+        let Some((pressure, mut handler)) = state.pressure.as_mut().and_then(|watch| watch.update(total_free)) else {
+            return;
         };
-    }    
 
+        handler(self, pressure);
 
-    /// Create a new allocator.
-    pub fn new(zero_initialized: bool) -> Pin<Box<Self>> {
+        // Don't undo a replacement or removal made by the handler
+        let state = unsafe { self.state_mut() };
+        if state.pressure_generation == generation {
+            if let Some(watch) = state.pressure.as_mut() {
+                watch.restore(handler);
+            }
+        }
+    }
+
+}
+
+impl<const M: usize, const B: usize> BuddyAllocator<M, B>
+where 
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Construct a new allocator in place and return it without pinning it.
+    /// Optionally, you can initialize the heap with `0` bytes by setting the `zero_initialized` flag.
+    /// 
+    /// # Safety
+    /// 
+    /// The returned allocator must immediately be pinned via `pin!()` before being used.
+    pub const unsafe fn new_unpinned(zero_initialized: bool) -> Self {
 
         let memory = if zero_initialized {
             [MaybeUninit::<u8>::zeroed(); M]
@@ -117,71 +568,163 @@ where
             [MaybeUninit::<u8>::uninit(); M]
         };
 
-        let mut res = Box::new(Self {
-            memory,
-            #[allow(invalid_value)]
-            alloc_table: unsafe { MaybeUninit::uninit().assume_init() },
-            proto_allocator: UnsafeCell::new(unsafe { FixedSizeAllocator::<{block_node_size!()}, {M / B}>::new_unpinned(false) }),
-            proto_allocator_pin: unsafe { Pin::new_unchecked(mem::transmute(NonNull::<Self::PinnedProtoAllocator>::dangling())) },
-            upper_memory_bound: NonNull::dangling(),
-            total_free: M,
-            _pin: PhantomPinned::default()
-        });
+        Self {
+            memory: UnsafeCell::new(memory),
+            state: UnsafeCell::new(State::new()),
+            _pin: PhantomPinned
+        }
+    }
 
-        // Get the lower bound of the heap
-        let base_ptr = unsafe { 
-            NonNull::new_unchecked(res.memory.as_mut_ptr() as *mut u8)
-        };
 
-        // Initialize the allocation table
-        res.alloc_table = BlockNode::new(M, base_ptr);
+    /// Initialize an allocator that has been pinned after being created with `new_unpinned()`.
+    /// The allocator holds no self-references, so there is nothing left to initialize. This function is kept for compatibility.
+    /// 
+    /// # Safety
+    /// 
+    /// The allocator must not be moved after this function is called.
+    pub unsafe fn init_pinned(self: Pin<&mut Self>) {}
 
-        // Calculate the upper bound of the heap
-        res.upper_memory_bound = unsafe {
-            NonNull::new_unchecked(base_ptr.as_ptr().byte_add(M))
-        };
-        
-        // Store a pin to the proto allocator
-        res.as_mut().proto_allocator_pin = unsafe {
-            Pin::new_unchecked(res.proto_allocator.get().as_mut_unchecked())
-        };
-        
-        Box::into_pin(res)
+
+    /// Create a new allocator with a zero-initialized heap, meant to be stored in a `static` or `thread_local!` variable.
+    /// The allocator holds no pointers, so the returned value is valid wherever it ends up and needs no further initialization.
+    /// 
+    /// Pointers to allocated blocks are only valid as long as the allocator is not moved, which a `static` never is.
+    /// Note that the allocator is not `Sync`, so a plain `static` should hold a `StaticBuddyAllocator` instead, which wraps it in a spin lock.
+    pub const fn new_static() -> Self {
+        // Moving the allocator doesn't break it: it only leaves the pointers it handed out dangling, and dereferencing them is already unsafe
+        unsafe { Self::new_unpinned(true) }
+    }
+
+
+    /// Create a new allocator.
+    /// The allocator is built directly on the heap, so its size is not limited by the stack size.
+    pub fn new(zero_initialized: bool) -> Pin<Box<Self>> {
+
+        let mut res = Box::<Self>::new_uninit();
+
+        unsafe {
+            Self::init_in_place(res.as_mut_ptr(), zero_initialized);
+            Box::into_pin(res.assume_init())
+        }
+    }
+
+
+    /// Initialize a new allocator at `this` without building it on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    pub(crate) unsafe fn init_in_place(this: *mut Self, zero_initialized: bool) {
+        unsafe {
+            // The heap is allowed to be uninitialized
+            if zero_initialized {
+                UnsafeCell::raw_get(&raw const (*this).memory).write_bytes(0, 1);
+            }
+            State::init_in_place(UnsafeCell::raw_get(&raw const (*this).state));
+        }
     }
 
 
     /// Allocate a memory block big enough to store at least the size of `T`.
     /// Return a pointer to the start of the allocated block.
     /// Pointers allocated throuch this allocator must be freed through this allocator as well.
-    pub fn alloc<T>(self: Pin<&mut Self>) -> Result<NonNull<T>, AllocError> {
-        unsafe {
-            mem::transmute::<Result<NonNull<u8>, AllocError>, Result<NonNull<T>, AllocError>>(
-                self.alloc_bytes(mem::size_of::<T>())
-            )
-        }
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc<T>(&self) -> Result<NonNull<T>, AllocError> {
+        self.alloc_bytes(mem::size_of::<T>())
+            .map(NonNull::cast)
     }
 
 
     /// Allocate a memory block big enough to store at least `size` bytes.
     /// Return a pointer to the start of the allocated block.
     /// Pointers allocated throuch this allocator must be freed through this allocator as well.
-    pub fn alloc_bytes(self: Pin<&mut Self>, size: usize) -> Result<NonNull<u8>, AllocError> {
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_bytes(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+
+        let handle = self.alloc_handle(size)?;
+        Ok(self.resolve(handle))
+    }
+
 
-        let self_mut = unsafe { self.get_unchecked_mut() };
+    /// Allocate a memory block big enough to store at least `size` bytes.
+    /// Return a position-independent handle to the allocated block, which can be turned into a pointer through `resolve()`.
+    /// Handles allocated through this allocator must be freed through this allocator as well.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_handle(&self, size: usize) -> Result<BlockHandle, AllocError> {
+        self.alloc_handle_placed(size, self.placement())
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes toward the end of the heap chosen by `placement`,
+    /// regardless of the placement policy of the allocator.
+    /// Return a pointer to the start of the allocated block.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_bytes_placed(&self, size: usize, placement: Placement) -> Result<NonNull<u8>, AllocError> {
+
+        let handle = self.alloc_handle_placed(size, placement)?;
+        Ok(self.resolve(handle))
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes toward the end of the heap chosen by `placement`,
+    /// regardless of the placement policy of the allocator.
+    /// Return a position-independent handle to the allocated block.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_handle_placed(&self, size: usize, placement: Placement) -> Result<BlockHandle, AllocError> {
+
+        let res = loop {
+
+            let res = self.try_alloc_handle(size, placement);
+            if !matches!(res, Err(AllocError::OutOfMemory)) {
+                break res;
+            }
+
+            // The state is not borrowed while the handler runs, so that it can free memory
+            let state = unsafe { self.state_mut() };
+            let generation = state.oom_generation;
+            let Some(mut handler) = state.oom_handler.take() else {
+                break res;
+            };
+
+            let action = handler(self, size);
+
+            // Don't undo a replacement or removal made by the handler
+            let state = unsafe { self.state_mut() };
+            if state.oom_generation == generation {
+                state.oom_handler = Some(handler);
+            }
+
+            if action == OomAction::Fail {
+                break res;
+            }
+        };
+
+        self.check_pressure();
+        res
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes toward the end of the heap chosen by `placement`, without calling any handler.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn try_alloc_handle(&self, size: usize, placement: Placement) -> Result<BlockHandle, AllocError> {
+
+        let state = unsafe { self.state_mut() };
 
         if size == 0 {
             // Disallow allocating zero bytes.
             // Think: if zero bytes were to be allocated, what is the returned pointer supposed to point to?
             Err(AllocError::ZeroAllocation)
 
-        } else if size > self_mut.total_free() {
+        } else if let Some(max_order) = state.max_alloc_order.filter(|&order| size > B << order) {
+            Err(AllocError::ExceedsMaxOrder { max_order, max_size: B << max_order })
+
+        } else if size > state.total_free {
             // Cannot ever allocate more than the total free memory
             Err(AllocError::OutOfMemory)
-            
-        } else if let Some((ptr, allocated)) = self_mut.alloc_table.alloc(size, &mut self_mut.proto_allocator_pin) {
-            // Keep track of the free memory
-            self_mut.total_free -= allocated;
-            Ok(ptr)
+
+        } else if let Some((offset, allocated)) = state.take_block(size, placement) {
+            state.record_alloc(self.heap_base(), offset, size, allocated);
+            Ok(BlockHandle::from_offset(offset))
 
         } else {
             Err(AllocError::OutOfMemory)
@@ -189,28 +732,127 @@ where
     }
 
 
+    /// Allocate the block that starts at `ptr` and is big enough to store at least `size` bytes, splitting bigger blocks as needed.
+    /// Fail with `AllocError::RangeOutOfBounds` if `ptr` lies outside of the heap, with `AllocError::MisalignedAddress` if it's not aligned to the block size,
+    /// or with `AllocError::RangeInUse` if any part of the block is already allocated or reserved.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_at(&self, ptr: NonNull<u8>, size: usize) -> Result<NonNull<u8>, AllocError> {
+
+        let state = unsafe { self.state_mut() };
+
+        if size == 0 {
+            return Err(AllocError::ZeroAllocation);
+        }
+
+        let block_size = size.max(B).checked_next_power_of_two().unwrap_or(usize::MAX);
+        let offset = self.handle_of(ptr).ok_or(AllocError::RangeOutOfBounds)?.offset();
+
+        if let Some(max_order) = state.max_alloc_order.filter(|&order| size > B << order) {
+            return Err(AllocError::ExceedsMaxOrder { max_order, max_size: B << max_order });
+        }
+        if block_size > M {
+            return Err(AllocError::OutOfMemory);
+        }
+        if !offset.is_multiple_of(block_size) {
+            return Err(AllocError::MisalignedAddress { alignment: block_size });
+        }
+
+        // Cached blocks and deferred merges would make a free block look allocated
+        state.flush_magazines();
+        state.alloc_table.coalesce();
+
+        if !state.alloc_table.claim(offset, block_size) {
+            return Err(AllocError::RangeInUse);
+        }
+
+        state.record_alloc(self.heap_base(), offset, size, block_size);
+        self.check_pressure();
+        Ok(ptr)
+    }
+
+
+    /// Reserve a block of order `ORDER`, that is `B << ORDER` bytes, and return a child allocator managing just that block.
+    /// The child has its own allocation tree and statistics. When it's dropped, the block goes back to this allocator.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_sub_allocator<const ORDER: usize>(&self) -> Result<SubAllocator<'_, M, B, ORDER>, AllocError>
+    where 
+        [(); 1 << ORDER]:,
+    {
+        SubAllocator::new(self)
+    }
+
+
     /// Free the memory block found at `ptr`.
     /// Note that the block must have been allocated through this allocator.
-    pub fn free_nonnull<T>(self: Pin<&mut Self>, ptr: NonNull<T>) -> Result<(), FreeError> {
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn free_nonnull<T>(&self, ptr: NonNull<T>) -> Result<(), FreeError> {
 
-        let self_data = unsafe { self.get_unchecked_mut() };
+        // Only compare addresses, so that `ptr` may come from anywhere
+        let base_address = self.heap_base().as_ptr().addr();
+        let address = ptr.as_ptr().addr();
 
-        // Drop the generic type. It's irrelevant which type the pointer points to.
-        let ptr = unsafe {
-            mem::transmute::<NonNull<T>, NonNull<u8>>(ptr)
-        };
+        if address < base_address {
+            // Cannot free memory outside of the allocator's heap
+            Err(FreeError::FreeOutOfBounds)
+        } else {
+            // Out-of-bounds pointers past the end of the heap are caught by `free_handle()`
+            self.free_handle(BlockHandle::from_offset(address - base_address))
+        }
+    }
+
+
+    /// Free the memory block referenced by `handle`.
+    /// Note that the block must have been allocated through this allocator.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn free_handle(&self, handle: BlockHandle) -> Result<(), FreeError> {
+
+        let res = self.free_handle_unchecked(handle);
+        self.check_pressure();
+        res
+    }
+
+
+    /// Free the memory block referenced by `handle`, without calling the pressure handler.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn free_handle_unchecked(&self, handle: BlockHandle) -> Result<(), FreeError> {
 
-        if ptr >= self_data.upper_memory_bound || (ptr.as_ptr() as usize) < (self_data.memory.as_ptr() as usize) {
+        let state = unsafe { self.state_mut() };
+
+        if handle.offset() >= M {
             // Cannot free memory outside of the allocator's heap
             Err(FreeError::FreeOutOfBounds)
 
         } else {
 
-            match self_data.alloc_table.free(ptr, &mut self_data.proto_allocator_pin) {
+            #[cfg(feature = "debug-heap")]
+            if state.quarantine.policy().is_some() {
+                return state.quarantine_block(self.heap_base(), handle);
+            }
+
+            let cached = if state.magazines.config().is_some() {
+                state.cache_block(handle.offset())?
+            } else {
+                None
+            };
+
+            // Cached blocks stay allocated in the allocation tree, but count as free memory
+            match cached.map_or_else(|| state.alloc_table.free(handle.offset()), Ok) {
 
                 Ok(freed) => {
                     // Keep track of the free memory
-                    self_data.total_free += freed;
+                    state.total_free += freed;
+
+                    #[cfg(feature = "track-allocations")]
+                    state.tracker.record_free(handle.offset() / B);
+
+                    #[cfg(feature = "debug-heap")]
+                    {
+                        let overflowed = state.poison_freed_block(self.heap_base(), handle.offset(), freed);
+                        if overflowed {
+                            return Err(FreeError::BufferOverflow);
+                        }
+                    }
+
                     Ok(())
                 },
 
@@ -220,9 +862,39 @@ where
     }
 
 
+    /// Configure the quarantine of freed blocks. Pass `None` to disable it.
+    /// While the quarantine is enabled, freed blocks are poisoned and kept allocated instead of being merged with their buddies.
+    /// When the policy releases them, their poison is checked to detect writes through dangling pointers.
+    /// Quarantined blocks cannot be allocated and don't count as free memory.
+    #[cfg(feature = "debug-heap")]
+    pub fn set_quarantine(&self, policy: Option<QuarantinePolicy>) -> Result<(), FreeError> {
+
+        let state = unsafe { self.state_mut() };
+
+        state.quarantine.set_policy(policy);
+        state.release_quarantined(self.heap_base(), false)
+    }
+
+
+    /// Release every quarantined block.
+    /// Fail with `FreeError::UseAfterFree` if any of them was written after being freed. Every block is released regardless.
+    #[cfg(feature = "debug-heap")]
+    pub fn flush_quarantine(&self) -> Result<(), FreeError> {
+        unsafe { self.state_mut() }.release_quarantined(self.heap_base(), true)
+    }
+
+
+    /// Return the total size of the blocks held in the quarantine.
+    #[cfg(feature = "debug-heap")]
+    pub fn total_quarantined(&self) -> usize {
+        self.state().quarantine.bytes()
+    }
+
+
     /// Free the memory block found at `ptr`.
     /// Note that the block must have been allocated through this allocator.
-    pub fn free<T>(self: Pin<&mut Self>, ptr: *const T) -> Result<(), FreeError> {
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn free<T>(&self, ptr: *const T) -> Result<(), FreeError> {
 
         if let Some(ptr) = NonNull::new(ptr as *mut u8) {
             self.free_nonnull(ptr)
@@ -232,10 +904,89 @@ where
     }
 
 
+    /// Return a pointer to the memory block referenced by `handle`.
+    /// Note that the returned pointer is only valid as long as the allocator is not moved, which pinning already guarantees.
+    pub fn resolve(&self, handle: BlockHandle) -> NonNull<u8> {
+        debug_assert!(handle.offset() < M, "Block handle out of the heap bounds");
+        unsafe {
+            self.heap_base().byte_add(handle.offset())
+        }
+    }
+
+
+    /// Return the position-independent handle of the memory block that starts at `ptr`.
+    /// Return `None` if `ptr` lies outside of the allocator's heap.
+    pub fn handle_of<T>(&self, ptr: NonNull<T>) -> Option<BlockHandle> {
+
+        let base_address = self.heap_base().as_ptr().addr();
+        let address = ptr.as_ptr().addr();
+
+        if address < base_address || address >= base_address + M {
+            None
+        } else {
+            Some(BlockHandle::from_offset(address - base_address))
+        }
+    }
+
+
+    /// Mark every zero-order block that overlaps the `len` bytes starting at offset `start` as permanently allocated, splitting blocks as needed.
+    /// Reserved blocks are never handed out and cannot be freed, even through `free_all()`. They count neither as free nor as allocated memory.
+    /// Fail with `AllocError::RangeOutOfBounds` if the range exceeds the heap, or with `AllocError::RangeInUse` if any part of it is allocated or reserved already.
+    /// Nothing is reserved in that case.
+    pub fn reserve_range(&self, start: usize, len: usize) -> Result<(), AllocError> {
+
+        let state = unsafe { self.state_mut() };
+
+        if len == 0 {
+            return Err(AllocError::ZeroAllocation);
+        }
+
+        let end = start.checked_add(len)
+            .filter(|&end| end <= M)
+            .ok_or(AllocError::RangeOutOfBounds)?;
+
+        // Cached blocks are free memory, so they must not get in the way
+        state.flush_magazines();
+
+        let reserved = state.alloc_table.reserved();
+        if !state.alloc_table.reserve_range(start - start % B, end.next_multiple_of(B)) {
+            return Err(AllocError::RangeInUse);
+        }
+
+        state.total_free -= state.alloc_table.reserved() - reserved;
+        self.check_pressure();
+        Ok(())
+    }
+
+
+    /// Return the total size of the reserved blocks.
+    pub fn total_reserved(&self) -> usize {
+        self.state().alloc_table.reserved()
+    }
+
+
+    /// Walk the whole allocation tree and check that it's consistent.
+    /// Return a description of the first inconsistency found, if any.
+    /// The check takes time proportional to the number of tree nodes.
+    pub fn check_integrity(&self) -> Result<(), IntegrityError> {
+
+        let state = self.state();
+
+        // Cached blocks are allocated in the allocation tree, but count as free memory
+        let free = state.alloc_table.check_integrity()? + state.magazines.bytes();
+
+        if free != state.total_free {
+            return Err(IntegrityError::FreeMemoryMismatch { recorded: state.total_free, actual: free });
+        }
+
+        Ok(())
+    }
+
+
     /// Return the total amount of free memory in the heap.
     /// Note that this memory may not be usable as a whole because of fragmentation.
-    pub const fn total_free(&self) -> usize {
-        self.total_free
+    pub fn total_free(&self) -> usize {
+        self.state().total_free
     }
 
 
@@ -246,18 +997,136 @@ where
 
 
     /// Return the size of allocated memory. That is, the amount of memory that is currently in use.
-    pub const fn total_allocated(&self) -> usize {
-        self.heap_size() - self.total_free()
+    /// Reserved blocks are not included.
+    pub fn total_allocated(&self) -> usize {
+        self.heap_size() - self.total_free() - self.total_reserved()
+    }
+
+
+    /// Serialize the state of the allocation tree into a compact, checksummed snapshot.
+    /// The snapshot can be restored into any allocator with the same `M` and `B` through `restore()`.
+    /// Note that the heap contents are not included. Use `snapshot_with_heap()` to include them as well.
+    /// 
+    /// As a side effect, every block cached in the magazines is flushed back into the allocation tree first, like `trim()` does,
+    /// so that the snapshot records them as free. The magazines are empty afterwards.
+    /// The quarantine is flushed as well, without reporting writes after free. Call `flush_quarantine()` beforehand to check for them.
+    pub fn snapshot(&self) -> Vec<u8> {
+
+        let state = unsafe { self.state_mut() };
+        state.flush_magazines();
+        #[cfg(feature = "debug-heap")]
+        let _ = state.release_quarantined(self.heap_base(), true);
+
+        let mut tree = Vec::new();
+        state.alloc_table.encode(&mut tree);
+
+        snapshot::write_snapshot(M, B, state.total_free, &tree, None)
+    }
+
+
+    /// Serialize the state of the allocation tree and the contents of the heap into a compact, checksummed snapshot.
+    /// Like `snapshot()`, it flushes the magazines and the quarantine as a side effect.
+    /// 
+    /// # Safety
+    /// 
+    /// Every byte of the heap must be initialized. This is always the case for zero-initialized allocators.
+    pub unsafe fn snapshot_with_heap(&self) -> Vec<u8> {
+
+        let state = unsafe { self.state_mut() };
+        state.flush_magazines();
+        #[cfg(feature = "debug-heap")]
+        let _ = state.release_quarantined(self.heap_base(), true);
+
+        let mut tree = Vec::new();
+        state.alloc_table.encode(&mut tree);
+
+        let heap = unsafe {
+            slice::from_raw_parts(self.heap_base().as_ptr() as *const u8, M)
+        };
+
+        snapshot::write_snapshot(M, B, state.total_free, &tree, Some(heap))
+    }
+
+
+    /// Replace the state of the allocator with the one stored in `snapshot`.
+    /// If the snapshot includes the heap contents, they are copied into the heap as well.
+    /// The snapshot is fully validated before being applied, so the allocator is left untouched if an error is returned.
+    /// 
+    /// # Safety
+    /// 
+    /// This function will invalidate all pointers to previously allocated blocks.
+    pub unsafe fn restore(&self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+
+        let parsed = snapshot::parse_snapshot(snapshot, M, B, 2 * (M / B))?;
+
+        // Discard the current allocation tree before rebuilding it
+        unsafe {
+            self.free_all();
+        }
+
+        let state = unsafe { self.state_mut() };
+        state.alloc_table.decode(parsed.tree);
+        state.total_free = parsed.total_free;
+
+        if let Some(heap) = parsed.heap {
+            unsafe {
+                ptr::copy_nonoverlapping(heap.as_ptr(), self.heap_base().as_ptr(), M);
+            }
+        }
+
+        Ok(())
     }
 
 
-    /// Free the entirety of the heap. 
+    /// Free the entirety of the heap, except for the reserved ranges.
+    /// 
+    /// # Safety
+    /// 
     /// This function is inherently unsafe because it will invalidate all pointers to previously allocated blocks.
-    pub unsafe fn free_all(&mut self) {
-        self.alloc_table = BlockNode::new(M, self.alloc_table.block_address);
-        self.total_free = M;
-        self.proto_allocator_pin.as_mut().free_all();
+    pub unsafe fn free_all(&self) {
+
+        let state = unsafe { self.state_mut() };
+
+        state.alloc_table.clear_allocations();
+        state.total_free = M - state.alloc_table.reserved();
+        state.magazines.clear();
+        #[cfg(feature = "debug-heap")]
+        {
+            state.requested_sizes.fill(0);
+            state.quarantine.clear();
+        }
+        #[cfg(feature = "track-allocations")]
+        state.tracker.clear();
     }
 
 }
 
+impl<const M: usize, const B: usize> Drop for BuddyAllocator<M, B>
+where 
+    [(); M / B]:
+{
+
+    fn drop(&mut self) {
+
+        let leak_check = self.state.get_mut().leak_check;
+
+        if leak_check == LeakCheck::Off {
+            return;
+        }
+
+        let leaks = self.leak_report();
+        if leaks.is_empty() {
+            return;
+        }
+
+        let message = format!("Buddy allocator dropped with {} leaked blocks: {:#?}", leaks.len(), leaks);
+
+        // Don't turn an ongoing panic into an abort
+        if leak_check == LeakCheck::Panic && !thread::panicking() {
+            panic!("{}", message);
+        } else {
+            eprintln!("{}", message);
+        }
+    }
+
+}
@@ -0,0 +1,50 @@
+/// When an allocator merges free buddy blocks back into bigger blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoalescingPolicy {
+
+    /// Merge free buddies as soon as a block is freed, which keeps fragmentation as low as possible.
+    #[default]
+    Eager,
+
+    /// Only merge free buddies when an allocation cannot be satisfied otherwise.
+    /// Workloads that keep freeing and allocating blocks of the same size then reuse the free buddies instead of merging and splitting them every time.
+    Lazy,
+
+    /// Defer merges until more than this many pairs of free buddies are waiting to be merged, then merge all of them.
+    /// Allocations that cannot be satisfied otherwise trigger the merges as well.
+    Watermark(usize),
+
+}
+
+
+/// Counters of the split and merge operations performed on the allocation tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CoalescingStats {
+
+    /// Number of blocks split in two buddies.
+    pub splits: usize,
+
+    /// Number of pairs of buddies merged back into a single block.
+    pub merges: usize,
+
+    /// Number of times freeing a block left two free buddies unmerged because of the coalescing policy.
+    pub deferred_merges: usize,
+
+    /// Number of deferred merges that never had to be performed because one of the buddies got allocated again.
+    /// Each of them also saved the split that would have followed the merge.
+    pub merges_saved: usize,
+
+}
+
+impl CoalescingStats {
+
+    pub(crate) const fn new() -> Self {
+        Self {
+            splits: 0,
+            merges: 0,
+            deferred_merges: 0,
+            merges_saved: 0
+        }
+    }
+
+}
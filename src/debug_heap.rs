@@ -0,0 +1,205 @@
+use std::ptr::NonNull;
+
+
+/// Pattern freshly allocated blocks are filled with.
+pub const ALLOC_PATTERN: u8 = 0xAB;
+
+/// Pattern freed blocks are filled with.
+pub const FREE_PATTERN: u8 = 0xDD;
+
+/// Pattern of the canary bytes stored between the end of the requested size and the end of the allocated block.
+pub const RED_ZONE_PATTERN: u8 = 0xFD;
+
+
+/// Fill a freshly allocated block of `allocated` bytes, `requested` of which were asked for.
+/// The requested bytes are poisoned and the rounding slack becomes a red zone.
+/// 
+/// # Safety
+/// 
+/// `block` must point to `allocated` writable bytes.
+pub unsafe fn poison_alloc(block: NonNull<u8>, requested: usize, allocated: usize) {
+    unsafe {
+        block.write_bytes(ALLOC_PATTERN, requested);
+        block.add(requested).write_bytes(RED_ZONE_PATTERN, allocated - requested);
+    }
+}
+
+
+/// Return whether the red zone of a block of `allocated` bytes, `requested` of which were asked for, is still intact.
+/// 
+/// # Safety
+/// 
+/// `block` must point to `allocated` readable bytes whose red zone was written by `poison_alloc()`.
+pub unsafe fn check_red_zone(block: NonNull<u8>, requested: usize, allocated: usize) -> bool {
+    (requested..allocated).all(|i| unsafe { *block.as_ptr().add(i) } == RED_ZONE_PATTERN)
+}
+
+
+/// Fill a freed block of `size` bytes with the free pattern.
+/// 
+/// # Safety
+/// 
+/// `block` must point to `size` writable bytes.
+pub unsafe fn poison_free(block: NonNull<u8>, size: usize) {
+    unsafe {
+        block.write_bytes(FREE_PATTERN, size);
+    }
+}
+
+
+/// Return whether a freed block of `size` bytes still holds the free pattern.
+/// 
+/// # Safety
+/// 
+/// `block` must point to `size` readable bytes that were written by `poison_free()`.
+pub unsafe fn check_free_poison(block: NonNull<u8>, size: usize) -> bool {
+    (0..size).all(|i| unsafe { *block.as_ptr().add(i) } == FREE_PATTERN)
+}
+
+
+/// When quarantined blocks are released back to the allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarantinePolicy {
+
+    /// Release a block once more than this many blocks are quarantined.
+    Frees(usize),
+
+    /// Release the oldest blocks once more than this many bytes are quarantined.
+    Bytes(usize),
+
+}
+
+
+/// FIFO of freed blocks that are kept allocated and poisoned to detect writes after they are freed.
+/// Holds at most `N` blocks, which is enough for every zero-order block of the heap.
+pub(crate) struct Quarantine<const B: usize, const N: usize> {
+
+    policy: Option<QuarantinePolicy>,
+
+    /// Ring buffer of quarantined blocks as (offset, size) pairs.
+    entries: [(usize, usize); N],
+
+    /// Whether the block starting at each zero-order block is quarantined, so that quarantined blocks can be told apart in constant time.
+    quarantined: [bool; N],
+
+    /// Index of the oldest quarantined block.
+    head: usize,
+
+    /// Number of quarantined blocks.
+    len: usize,
+
+    /// Total size of the quarantined blocks.
+    bytes: usize,
+
+}
+
+impl<const B: usize, const N: usize> Quarantine<B, N> {
+
+    pub const fn new() -> Self {
+        Self {
+            policy: None,
+            entries: [(0, 0); N],
+            quarantined: [false; N],
+            head: 0,
+            len: 0,
+            bytes: 0
+        }
+    }
+
+
+    /// Initialize an empty quarantine at `this` without building it on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    pub unsafe fn init_in_place(this: *mut Self) {
+        unsafe {
+            (&raw mut (*this).policy).write(None);
+            (&raw mut (*this).entries).write_bytes(0, 1);
+            (&raw mut (*this).quarantined).write_bytes(0, 1);
+            (&raw mut (*this).head).write(0);
+            (&raw mut (*this).len).write(0);
+            (&raw mut (*this).bytes).write(0);
+        }
+    }
+
+
+    pub const fn policy(&self) -> Option<QuarantinePolicy> {
+        self.policy
+    }
+
+
+    /// Change the policy. Blocks that are already quarantined stay so until they are released.
+    pub fn set_policy(&mut self, policy: Option<QuarantinePolicy>) {
+        self.policy = policy;
+    }
+
+
+    /// Return the total size of the quarantined blocks.
+    pub const fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+
+    /// Return whether the block at `offset` is quarantined.
+    pub fn contains(&self, offset: usize) -> bool {
+        offset.is_multiple_of(B) && self.quarantined[offset / B]
+    }
+
+
+    /// Append a block to the quarantine.
+    pub fn push(&mut self, offset: usize, size: usize) {
+        debug_assert!(self.len < N, "Quarantine overflow");
+        self.entries[(self.head + self.len) % N] = (offset, size);
+        self.quarantined[offset / B] = true;
+        self.len += 1;
+        self.bytes += size;
+    }
+
+
+    /// Remove and return the oldest block, if there is any.
+    pub fn pop(&mut self) -> Option<(usize, usize)> {
+
+        if self.len == 0 {
+            None
+        } else {
+            let entry = self.entries[self.head];
+            self.quarantined[entry.0 / B] = false;
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+            self.bytes -= entry.1;
+            Some(entry)
+        }
+    }
+
+
+    /// Remove and return the oldest block if the quarantine holds more than the policy allows.
+    pub fn pop_expired(&mut self) -> Option<(usize, usize)> {
+
+        let expired = match self.policy {
+            Some(QuarantinePolicy::Frees(max_blocks)) => self.len > max_blocks,
+            Some(QuarantinePolicy::Bytes(max_bytes)) => self.bytes > max_bytes,
+            // Without a policy, nothing should stay quarantined
+            None => self.len > 0
+        };
+
+        if expired {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+
+    /// Forget every quarantined block.
+    pub fn clear(&mut self) {
+        for i in 0..self.len {
+            self.quarantined[self.entries[(self.head + i) % N].0 / B] = false;
+        }
+        self.head = 0;
+        self.len = 0;
+        self.bytes = 0;
+    }
+
+}
+
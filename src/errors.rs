@@ -1,7 +1,9 @@
 
 
 /// Enum representing errors that may happen when freeing memory blocks.
+/// Optional features may add variants, so the enum is non-exhaustive.
 #[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
 pub enum FreeError {
 
     /// The memory chunk is already free
@@ -11,19 +13,146 @@ pub enum FreeError {
     /// The freed pointer was null
     NullPtrFree,
     /// The freed pointer was out of the heap bounds
-    FreeOutOfBounds
+    FreeOutOfBounds,
+    /// The block belongs to a reserved range, which can never be freed
+    ReservedBlock,
+    /// The red zone past the requested size of the block was overwritten. The block was still freed, so it must not be freed again.
+    /// Only reported with the `debug-heap` feature
+    BufferOverflow,
+    /// Releasing the quarantined block at the given handle revealed it was written after being freed.
+    /// Only reported with the `debug-heap` feature
+    UseAfterFree(crate::BlockHandle),
 
 }
 
 
 /// Enum representing errors that may happen when allocating of memory blocks.
+/// New kinds of allocation may add variants, so the enum is non-exhaustive.
 #[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
 pub enum AllocError {
 
     /// Not enough memory to perform the requested allocation
     OutOfMemory,
     /// The requested allocation size was 0 bytes
     ZeroAllocation,
+    /// The requested allocation size exceeds the maximum allocation order, whose block size is `max_size` bytes
+    ExceedsMaxOrder { max_order: usize, max_size: usize },
+    /// The requested range does not fit in the heap
+    RangeOutOfBounds,
+    /// Part of the requested range is already allocated or reserved
+    RangeInUse,
+    /// The requested address is not aligned to the size of the block that would start there
+    MisalignedAddress { alignment: usize },
 
 }
 
+
+
+/// Enum representing errors that may happen when restoring an allocator from a snapshot.
+#[derive(Debug, Clone, Copy)]
+pub enum SnapshotError {
+
+    /// The data does not start with the snapshot magic number
+    InvalidMagic,
+    /// The snapshot was produced by an incompatible version of the format
+    UnsupportedVersion(u16),
+    /// The snapshot was taken from an allocator with a different heap size or zero-order block size
+    GeometryMismatch,
+    /// The data ends before the snapshot is complete
+    Truncated,
+    /// The checksum does not match the snapshot contents
+    ChecksumMismatch,
+    /// The allocation tree encoded in the snapshot is malformed
+    CorruptedTree,
+
+}
+
+
+/// Enum representing errors that may happen when creating or opening a persistent heap.
+#[derive(Debug, Clone, Copy)]
+pub enum PersistentHeapError {
+
+    /// An I/O operation on the backing file failed
+    Io(std::io::ErrorKind),
+    /// The backing file is not a persistent heap or was created by an incompatible version
+    InvalidHeader,
+    /// The backing file was created for a heap with a different heap size or zero-order block size
+    GeometryMismatch,
+    /// The heap was shut down cleanly, but its allocation metadata is inconsistent
+    CorruptedMetadata,
+
+}
+
+impl From<std::io::Error> for PersistentHeapError {
+
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error.kind())
+    }
+
+}
+
+
+/// Enum representing errors that may happen when creating or opening a shared heap.
+#[derive(Debug, Clone, Copy)]
+pub enum SharedHeapError {
+
+    /// An I/O operation on the shared memory segment failed
+    Io(std::io::ErrorKind),
+    /// The segment is not a shared heap, was created by an incompatible version or is not initialized yet
+    InvalidHeader,
+    /// The segment was created for a heap with a different heap size or zero-order block size
+    GeometryMismatch,
+    /// The process-shared lock could not be acquired or released
+    LockFailed(std::io::ErrorKind),
+    /// The allocation failed
+    Alloc(AllocError),
+    /// The free failed
+    Free(FreeError),
+
+}
+
+impl From<std::io::Error> for SharedHeapError {
+
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error.kind())
+    }
+
+}
+
+impl From<AllocError> for SharedHeapError {
+
+    fn from(error: AllocError) -> Self {
+        Self::Alloc(error)
+    }
+
+}
+
+impl From<FreeError> for SharedHeapError {
+
+    fn from(error: FreeError) -> Self {
+        Self::Free(error)
+    }
+
+}
+
+
+/// Enum describing the first inconsistency found in the allocation tree by an integrity check.
+/// Blocks are identified by their offset from the start of the heap and their size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+
+    /// The block doesn't have the offset or size its position in the tree requires, so it doesn't tile its parent exactly
+    BadTiling { expected_offset: usize, expected_size: usize, offset: usize, size: usize },
+    /// A zero-order block was split
+    SplitZeroOrderBlock { offset: usize },
+    /// Both children of the block are free, so they should have been merged
+    UnmergedBuddies { offset: usize, size: usize },
+    /// The children of the block are not stored in a valid slot of the internal node pool
+    ForeignNode { offset: usize, size: usize },
+    /// The number of nodes reachable from the root doesn't match the number of nodes in use in the internal node pool
+    NodeCountMismatch { reachable: usize, allocated: usize },
+    /// The recorded amount of free memory doesn't match the sum of the free blocks
+    FreeMemoryMismatch { recorded: usize, actual: usize },
+
+}
@@ -0,0 +1,23 @@
+
+/// A position-independent reference to an allocated memory block.
+/// The handle stores the offset of the block from the start of the heap, so it stays meaningful
+/// even if the heap is copied, persisted or mapped at another address.
+/// Use `BuddyAllocator::resolve()` to obtain a pointer to the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockHandle(usize);
+
+impl BlockHandle {
+
+    /// Create a handle from a raw offset relative to the heap base.
+    pub const fn from_offset(offset: usize) -> Self {
+        Self(offset)
+    }
+
+
+    /// Return the offset of the block relative to the heap base.
+    pub const fn offset(self) -> usize {
+        self.0
+    }
+
+}
+
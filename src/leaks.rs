@@ -0,0 +1,49 @@
+use std::ptr::NonNull;
+
+use crate::handle::BlockHandle;
+#[cfg(feature = "track-allocations")]
+use crate::tracking::CallSite;
+
+
+/// A block that is still allocated.
+/// Optional features may add fields, so the struct is non-exhaustive. It's only `Copy` and comparable without the `track-allocations` feature.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "track-allocations"), derive(Copy, PartialEq, Eq))]
+#[non_exhaustive]
+pub struct Leak {
+
+    /// Position-independent handle to the block.
+    pub handle: BlockHandle,
+
+    /// Start address of the block.
+    pub address: NonNull<u8>,
+
+    /// Size of the block in bytes.
+    pub size: usize,
+
+    /// Size originally requested for the block, if it's known. Only tracked with the `debug-heap` feature.
+    pub requested_size: Option<usize>,
+
+    /// Who allocated the block, if it's known. Only tracked with the `track-allocations` feature.
+    #[cfg(feature = "track-allocations")]
+    pub allocated_at: Option<CallSite>,
+
+}
+
+
+/// What an allocator should do if some blocks are still allocated when it's dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeakCheck {
+
+    /// Don't check for leaks.
+    #[default]
+    Off,
+
+    /// Print the leak report to the standard error.
+    Warn,
+
+    /// Panic with the leak report.
+    Panic,
+
+}
+
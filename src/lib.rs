@@ -1,14 +1,59 @@
 #![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
-#![feature(inherent_associated_types)]
-#![feature(ptr_as_ref_unchecked)]
 
 mod alloc_table;
 mod errors;
 mod buddy_allocator;
+mod static_allocator;
+mod handle;
+mod leaks;
+mod coalescing;
+mod placement;
+mod pressure;
+mod magazine;
+mod slab;
+mod arena;
+mod sub_allocator;
+mod zones;
+mod sync_allocator;
+#[cfg(feature = "track-allocations")]
+mod tracking;
+mod snapshot;
+#[cfg(feature = "debug-heap")]
+mod debug_heap;
+#[cfg(target_os = "linux")]
+mod sys;
+#[cfg(target_os = "linux")]
+mod region;
+#[cfg(target_os = "linux")]
+mod persistent;
+#[cfg(target_os = "linux")]
+mod shared;
 
-pub use errors::{AllocError, FreeError};
+pub use errors::{AllocError, FreeError, SnapshotError, PersistentHeapError, SharedHeapError, IntegrityError};
 pub use buddy_allocator::BuddyAllocator;
+pub use static_allocator::{StaticBuddyAllocator, StaticBuddyAllocatorGuard};
+pub use handle::BlockHandle;
+pub use leaks::{Leak, LeakCheck};
+pub use coalescing::{CoalescingPolicy, CoalescingStats};
+pub use placement::Placement;
+pub use pressure::{MemoryPressure, OomAction, OomHandler, PressureHandler};
+pub use magazine::{MagazineConfig, MAX_MAGAZINE_ORDERS};
+pub use slab::SlabCache;
+pub use arena::BuddyArena;
+pub use sub_allocator::SubAllocator;
+pub use zones::{Zone, ZonedAllocator};
+pub use sync_allocator::{SyncBuddyAllocator, AllocFuture};
+#[cfg(feature = "track-allocations")]
+pub use tracking::{AllocationInfo, CallSite};
+#[cfg(feature = "debug-heap")]
+pub use debug_heap::{ALLOC_PATTERN, FREE_PATTERN, RED_ZONE_PATTERN, QuarantinePolicy};
+#[cfg(target_os = "linux")]
+pub use persistent::PersistentHeap;
+#[cfg(target_os = "linux")]
+pub use shared::SharedHeap;
+#[cfg(target_os = "linux")]
+pub use region::RecoveryReport;
 
 
 #[cfg(test)]
@@ -17,7 +62,7 @@ mod tests {
     use std::{pin::pin, ptr::{self, NonNull}};
 
     use buddy_allocator::BuddyAllocator;
-    use errors::{AllocError, FreeError};
+    use errors::{AllocError, FreeError, SnapshotError, PersistentHeapError, SharedHeapError};
 
     use super::*;
 
@@ -34,58 +79,1176 @@ mod tests {
     #[test]
     fn check_allocator_bounds() {
 
-        let mut alloc = BuddyAllocator::<1024, 8>::new(false);
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
 
-        assert!(matches!(alloc.as_mut().alloc_bytes(0), Err(AllocError::ZeroAllocation)));
+        assert!(matches!(alloc.alloc_bytes(0), Err(AllocError::ZeroAllocation)));
 
-        assert!(matches!(alloc.as_mut().alloc_bytes(1025), Err(AllocError::OutOfMemory)));
+        assert!(matches!(alloc.alloc_bytes(1025), Err(AllocError::OutOfMemory)));
     }
 
 
     #[test]
     fn check_allocator_within_bounds() {
 
-        let mut alloc = BuddyAllocator::<1024, 8>::new(false);
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
 
-        assert!(alloc.as_mut().alloc_bytes(1).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(8).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(9).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(24).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(32).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(65).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(1000).is_err());
+        assert!(alloc.alloc_bytes(1).is_ok());
+        assert!(alloc.alloc_bytes(8).is_ok());
+        assert!(alloc.alloc_bytes(9).is_ok());
+        assert!(alloc.alloc_bytes(24).is_ok());
+        assert!(alloc.alloc_bytes(32).is_ok());
+        assert!(alloc.alloc_bytes(65).is_ok());
+        assert!(alloc.alloc_bytes(1000).is_err());
     }
 
 
     #[test]
     fn check_free_bounds() {
 
-        let mut alloc = BuddyAllocator::<1024, 8>::new(false);
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
 
-        assert!(matches!(alloc.as_mut().free(ptr::null() as *const u8), Err(FreeError::NullPtrFree)));
-        assert!(matches!(alloc.as_mut().free(usize::MAX as *const u8), Err(FreeError::FreeOutOfBounds)));
+        assert!(matches!(alloc.free(ptr::null::<u8>()), Err(FreeError::NullPtrFree)));
+        assert!(matches!(alloc.free(usize::MAX as *const u8), Err(FreeError::FreeOutOfBounds)));
     }
 
 
     #[test]
     fn check_full_free() {
 
-        let mut alloc = BuddyAllocator::<1024, 8>::new(false);
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
 
         let blocks = [
             1,2,3,4,5,6,7,8,9,32,32,53,12,76,50,21,127
         ];
 
         let ptrs: Vec<NonNull<u8>> = blocks.iter()
-            .map(|&s| alloc.as_mut().alloc_bytes(s as usize).unwrap())
+            .map(|&s| alloc.alloc_bytes(s as usize).unwrap())
             .collect();
 
         for ptr in ptrs {
-            assert!(alloc.as_mut().free_nonnull(ptr).is_ok());
+            assert!(alloc.free_nonnull(ptr).is_ok());
         }
 
         assert_eq!(alloc.total_free(), alloc.heap_size());
-    }   
+        assert!(alloc.leak_report().is_empty());
+    }
+
+
+    #[test]
+    fn check_zero_order_fill() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        // Splitting the whole heap into zero-order blocks needs the largest possible tree
+        let ptrs: Vec<NonNull<u8>> = (0..1024 / 8)
+            .map(|_| alloc.alloc_bytes(8).unwrap())
+            .collect();
+
+        assert_eq!(alloc.total_free(), 0);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+
+        for ptr in ptrs {
+            unsafe {
+                ptr.write(0xAA);
+            }
+            assert!(alloc.free_nonnull(ptr).is_ok());
+        }
+
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_shared_references() {
+
+        struct Stack<'a> {
+            alloc: &'a BuddyAllocator<1024, 8>,
+            blocks: Vec<NonNull<u8>>
+        }
+
+        impl Stack<'_> {
+
+            fn push(&mut self, size: usize) {
+                self.blocks.push(self.alloc.alloc_bytes(size).unwrap());
+            }
+
+            fn pop(&mut self) {
+                self.alloc.free_nonnull(self.blocks.pop().unwrap()).unwrap();
+            }
+        }
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        // Both structures allocate from the same allocator at the same time
+        let mut a = Stack { alloc: &alloc, blocks: Vec::new() };
+        let mut b = Stack { alloc: &alloc, blocks: Vec::new() };
+
+        a.push(16);
+        b.push(100);
+        a.push(8);
+        assert_eq!(alloc.total_allocated(), 16 + 128 + 8);
+
+        b.pop();
+        a.pop();
+        a.pop();
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_static_allocator() {
+
+        static HEAP: StaticBuddyAllocator<1024, 8> = StaticBuddyAllocator::new_static();
+
+        // The allocator is shared by every thread without any initialization
+        let handle = std::thread::spawn(|| HEAP.lock().alloc_handle(16)).join().unwrap().unwrap();
+        assert_eq!(HEAP.lock().total_allocated(), 16);
+
+        let heap = HEAP.lock();
+        assert!(HEAP.try_lock().is_none());
+        assert!(heap.free_handle(handle).is_ok());
+        assert_eq!(heap.total_free(), heap.heap_size());
+        drop(heap);
+        assert!(HEAP.try_lock().is_some());
+    }
+
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Too slow under Miri")]
+    fn check_deep_tree_small_stack() {
+
+        // A tree over 2^20 zero-order blocks is 20 levels deep
+        let alloc = BuddyAllocator::<{ 8 << 20 }, 8>::new(false);
+
+        let worker = std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || {
+
+                let handles: Vec<BlockHandle> = [8, 8, 24, 4096, 8, 1 << 20, 8]
+                    .into_iter()
+                    .map(|size| alloc.alloc_handle(size).unwrap())
+                    .collect();
+
+                for handle in handles {
+                    alloc.free_handle(handle).unwrap();
+                }
+
+                let freed = alloc.total_free() == alloc.heap_size();
+
+                // Resetting the per-block bookkeeping must not need a stack as large as the tree either
+                alloc.alloc_handle(4096).unwrap();
+                unsafe { alloc.free_all() };
+
+                freed && alloc.total_free() == alloc.heap_size()
+            })
+            .unwrap();
+
+        assert!(worker.join().unwrap());
+    }
+
+
+    #[test]
+    fn check_lazy_coalescing() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        alloc.set_coalescing_policy(CoalescingPolicy::Lazy);
+
+        // Reaching a zero-order block splits every level of the tree once
+        let mut ptr = alloc.alloc_bytes(8).unwrap();
+        assert_eq!(alloc.coalescing_stats().splits, 7);
+
+        // The freed block is reused as is, instead of being merged and split again
+        for _ in 0..10 {
+            alloc.free_nonnull(ptr).unwrap();
+            ptr = alloc.alloc_bytes(8).unwrap();
+        }
+
+        assert_eq!(alloc.coalescing_stats(), CoalescingStats { splits: 7, merges: 0, deferred_merges: 10, merges_saved: 10 });
+        assert_eq!(alloc.check_integrity(), Ok(()));
+
+        // An allocation that only fits once the buddies are merged triggers the deferred merges
+        alloc.free_nonnull(ptr).unwrap();
+        let whole = alloc.alloc_bytes(1024).unwrap();
+        assert_eq!(alloc.coalescing_stats().merges, 7);
+        assert!(alloc.free_nonnull(whole).is_ok());
+
+        // Watermark coalescing merges everything once too many merges are pending
+        alloc.set_coalescing_policy(CoalescingPolicy::Watermark(1));
+        let ptrs: Vec<NonNull<u8>> = (0..4)
+            .map(|_| alloc.alloc_bytes(8).unwrap())
+            .collect();
+        let stats = alloc.coalescing_stats();
+
+        for &ptr in &ptrs[..3] {
+            alloc.free_nonnull(ptr).unwrap();
+        }
+        assert_eq!(alloc.coalescing_stats().merges, stats.merges);
+
+        alloc.free_nonnull(ptrs[3]).unwrap();
+        assert_eq!(alloc.coalescing_stats().merges, stats.merges + 8);
+
+        alloc.set_coalescing_policy(CoalescingPolicy::Eager);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.snapshot(), BuddyAllocator::<1024, 8>::new(false).snapshot());
+    }
+
+
+    #[test]
+    fn check_magazines() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        alloc.set_magazines(Some(MagazineConfig { orders: 2, capacity: 2 }));
+
+        let mut ptr = alloc.alloc_bytes(16).unwrap();
+        let stats = alloc.coalescing_stats();
+
+        // Freed blocks of the cached orders go into their magazine and are handed out again without touching the tree
+        for _ in 0..10 {
+            alloc.free_nonnull(ptr).unwrap();
+            assert_eq!(alloc.total_cached(), 16);
+            assert_eq!(alloc.total_free(), 1024);
+            assert_eq!(alloc.check_integrity(), Ok(()));
+            assert!(alloc.leak_report().is_empty());
+
+            let new_ptr = alloc.alloc_bytes(9).unwrap();
+            assert_eq!(new_ptr, ptr);
+            ptr = new_ptr;
+        }
+        assert_eq!(alloc.coalescing_stats(), stats);
+
+        // Cached blocks cannot be freed twice, nor through a pointer inside them
+        alloc.free_nonnull(ptr).unwrap();
+        assert!(matches!(alloc.free_nonnull(ptr), Err(FreeError::DoubleFree)));
+        assert!(matches!(alloc.free_nonnull(unsafe { ptr.add(4) }), Err(FreeError::UnalignedFree)));
+        assert!(matches!(alloc.free_nonnull(unsafe { ptr.add(8) }), Err(FreeError::UnalignedFree)));
+
+        // Full magazines and uncached orders fall back to the tree
+        let ptrs: Vec<NonNull<u8>> = (0..3)
+            .map(|_| alloc.alloc_bytes(8).unwrap())
+            .collect();
+        let big = alloc.alloc_bytes(32).unwrap();
+        for &ptr in &ptrs {
+            alloc.free_nonnull(ptr).unwrap();
+        }
+        alloc.free_nonnull(big).unwrap();
+        assert_eq!(alloc.total_cached(), 16 + 16);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+
+        // Allocations that don't fit otherwise flush the magazines
+        let whole = alloc.alloc_bytes(1024).unwrap();
+        assert_eq!(alloc.total_cached(), 0);
+        alloc.free_nonnull(whole).unwrap();
+
+        let ptr = alloc.alloc_bytes(8).unwrap();
+        alloc.free_nonnull(ptr).unwrap();
+        alloc.trim();
+        assert_eq!(alloc.total_cached(), 0);
+        assert_eq!(alloc.total_free(), 1024);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+        assert_eq!(alloc.snapshot(), BuddyAllocator::<1024, 8>::new(false).snapshot());
+    }
+
+
+    #[test]
+    fn check_max_alloc_order() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        // Pre-splitting the heap into blocks of 256 bytes takes a split for the root and one for each of its children
+        alloc.set_max_alloc_order(Some(5), true);
+        assert_eq!(alloc.max_alloc_order(), Some(5));
+        assert_eq!(alloc.coalescing_stats().splits, 3);
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+
+        assert!(matches!(alloc.alloc_bytes(257), Err(AllocError::ExceedsMaxOrder { max_order: 5, max_size: 256 })));
+
+        let ptrs: Vec<NonNull<u8>> = (0..4)
+            .map(|_| alloc.alloc_bytes(256).unwrap())
+            .collect();
+        assert_eq!(alloc.total_free(), 0);
+
+        // The top-level blocks are not merged back when freed
+        for ptr in ptrs {
+            alloc.free_nonnull(ptr).unwrap();
+        }
+        assert_eq!(alloc.coalescing_stats().merges, 0);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+        assert_eq!(alloc.snapshot(), BuddyAllocator::<1024, 8>::new(false).snapshot());
+
+        // Lifting the cap merges the heap back into a single block
+        alloc.set_max_alloc_order(None, false);
+        assert_eq!(alloc.coalescing_stats().merges, 3);
+        let whole = alloc.alloc_bytes(1024).unwrap();
+        assert!(alloc.free_nonnull(whole).is_ok());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_slab_cache() {
+
+        let alloc = BuddyAllocator::<4096, 64>::new(false);
+        let mut cache = SlabCache::<u32, 4096, 64>::new(&alloc, 256);
+
+        let objects: Vec<NonNull<u32>> = (0..100)
+            .map(|i| {
+                let object = cache.alloc().unwrap();
+                unsafe {
+                    object.write(i);
+                }
+                object
+            })
+            .collect();
+
+        // Many objects share each slab instead of taking a zero-order block each
+        let slabs = cache.slab_count();
+        assert!(slabs < 100 / 8);
+        assert_eq!(alloc.total_allocated(), slabs * 256);
+        assert!(objects.iter().enumerate().all(|(i, object)| unsafe { object.read() } == i as u32));
+
+        let misaligned = unsafe { objects[0].byte_add(1) };
+        assert!(matches!(cache.free(misaligned), Err(FreeError::UnalignedFree)));
+
+        // Double frees and pointers into blocks that are not slabs of the cache are caught in every build
+        assert!(cache.free(objects[99]).is_ok());
+        assert!(matches!(cache.free(objects[99]), Err(FreeError::DoubleFree)));
+        let foreign = alloc.alloc::<u32>().unwrap();
+        assert!(matches!(cache.free(foreign), Err(FreeError::UnalignedFree)));
+        assert!(alloc.free_nonnull(foreign).is_ok());
+        assert_eq!(cache.in_use(), 99);
+
+        // Empty slabs go back to the allocator
+        for &object in &objects[..99] {
+            assert!(cache.free(object).is_ok());
+        }
+        assert_eq!(cache.in_use(), 0);
+        assert_eq!(cache.slab_count(), 0);
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+
+        // Dropping the cache returns the slabs still in use
+        cache.alloc().unwrap();
+        drop(cache);
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_arena() {
+
+        let alloc = BuddyAllocator::<4096, 64>::new(false);
+        let mut arena = BuddyArena::new(&alloc, 256);
+
+        let values: Vec<&mut u64> = (0..50)
+            .map(|i| arena.alloc(i as u64).unwrap())
+            .collect();
+        assert!(values.iter().enumerate().all(|(i, value)| **value == i as u64));
+
+        // Regular chunks hold many values, while larger allocations get a dedicated chunk
+        let chunks = arena.chunk_count();
+        assert!(chunks < 50 / 4);
+        let big = arena.alloc([0xABu8; 1000]).unwrap();
+        assert!(big.iter().all(|&byte| byte == 0xAB));
+        assert_eq!(arena.chunk_count(), chunks + 1);
+        assert_eq!(alloc.total_allocated(), chunks * 256 + 1024);
+
+        arena.reset();
+        assert_eq!(arena.chunk_count(), 0);
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+
+        arena.alloc(1u8).unwrap();
+        drop(arena);
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_sub_allocators() {
+
+        let alloc = BuddyAllocator::<4096, 16>::new(false);
+
+        // Each child reserves a block of 16 << 6 = 1024 bytes
+        let a = alloc.alloc_sub_allocator::<6>().unwrap();
+        let b = alloc.alloc_sub_allocator::<6>().unwrap();
+        assert_eq!(a.heap_size(), 1024);
+        assert_eq!(alloc.total_allocated(), 2048);
+
+        let ptrs: Vec<NonNull<u8>> = (0..8)
+            .map(|_| a.alloc_bytes(100).unwrap())
+            .collect();
+        assert!(matches!(a.alloc_bytes(100), Err(AllocError::OutOfMemory)));
+        let other = b.alloc_bytes(512).unwrap();
+
+        // Children keep their own statistics and cannot free each other's blocks
+        assert_eq!(a.total_free(), 0);
+        assert_eq!(b.total_allocated(), 512);
+        assert!(matches!(b.free_nonnull(ptrs[0]), Err(FreeError::FreeOutOfBounds)));
+        assert!(a.free_nonnull(ptrs[0]).is_ok());
+        assert_eq!(a.check_integrity(), Ok(()));
+
+        unsafe {
+            a.free_all();
+        }
+        assert_eq!(a.total_free(), a.heap_size());
+        assert_eq!(b.total_allocated(), 512);
+        assert!(b.free_nonnull(other).is_ok());
+
+        // Children that don't fit fail like any other allocation
+        assert!(alloc.alloc_sub_allocator::<8>().is_err());
+
+        drop(a);
+        drop(b);
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_reserved_ranges() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        let ptr = alloc.alloc_bytes(64).unwrap();
+
+        // The range covers the zero-order blocks from 96 to 328, which takes blocks of 32, 128 and 64 bytes, plus 8 bytes
+        assert!(alloc.reserve_range(100, 225).is_ok());
+        assert_eq!(alloc.total_reserved(), 232);
+        assert_eq!(alloc.total_allocated(), 64);
+        assert_eq!(alloc.total_free(), 1024 - 64 - 232);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+
+        assert!(matches!(alloc.reserve_range(0, 8), Err(AllocError::RangeInUse)));
+        assert!(matches!(alloc.reserve_range(320, 16), Err(AllocError::RangeInUse)));
+        assert!(matches!(alloc.reserve_range(1000, 100), Err(AllocError::RangeOutOfBounds)));
+        assert_eq!(alloc.total_reserved(), 232);
+
+        // Reserved blocks are never handed out nor freed, and they survive freeing the whole heap
+        assert!(matches!(alloc.free_handle(BlockHandle::from_offset(128)), Err(FreeError::ReservedBlock)));
+        let ptrs: Vec<NonNull<u8>> = std::iter::from_fn(|| alloc.alloc_bytes(8).ok()).collect();
+        let base = alloc.resolve(BlockHandle::from_offset(0)).as_ptr().addr();
+        assert!(ptrs.iter().all(|ptr| !(96..328).contains(&(ptr.as_ptr().addr() - base))));
+
+        unsafe {
+            alloc.free_all();
+        }
+        assert_eq!(alloc.total_reserved(), 232);
+        assert_eq!(alloc.total_free(), 1024 - 232);
+        assert!(alloc.leak_report().is_empty());
+        assert!(matches!(alloc.free_nonnull(ptr), Err(FreeError::DoubleFree)));
+
+        // Reservations are part of snapshots
+        let restored = BuddyAllocator::<1024, 8>::new(false);
+        unsafe {
+            restored.restore(&alloc.snapshot()).unwrap();
+        }
+        assert_eq!(restored.total_reserved(), 232);
+        assert_eq!(restored.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_alloc_at() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        let at = |offset| alloc.resolve(BlockHandle::from_offset(offset));
+
+        // The block of 64 bytes at offset 320 is claimed by splitting the blocks that contain it
+        let ptr = alloc.alloc_at(at(320), 50).unwrap();
+        assert_eq!(ptr, at(320));
+        assert_eq!(alloc.total_allocated(), 64);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+
+        assert!(matches!(alloc.alloc_at(at(328), 8), Err(AllocError::RangeInUse)));
+        assert!(matches!(alloc.alloc_at(at(256), 128), Err(AllocError::RangeInUse)));
+        assert!(matches!(alloc.alloc_at(at(96), 64), Err(AllocError::MisalignedAddress { alignment: 64 })));
+        assert!(matches!(alloc.alloc_at(at(0), 2048), Err(AllocError::OutOfMemory)));
+
+        let outside = unsafe { at(0).byte_add(1024) };
+        assert!(matches!(alloc.alloc_at(outside, 8), Err(AllocError::RangeOutOfBounds)));
+
+        // Regular allocations don't overlap the claimed block
+        let ptrs: Vec<NonNull<u8>> = std::iter::from_fn(|| alloc.alloc_bytes(64).ok()).collect();
+        assert_eq!(ptrs.len(), 1024 / 64 - 1);
+        assert!(!ptrs.contains(&ptr));
+
+        for ptr in ptrs {
+            assert!(alloc.free_nonnull(ptr).is_ok());
+        }
+        assert!(alloc.free_nonnull(ptr).is_ok());
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_placement() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        let base = alloc.resolve(BlockHandle::from_offset(0));
+        let offset = |ptr: NonNull<u8>| ptr.as_ptr().addr() - base.as_ptr().addr();
+
+        alloc.set_placement(Placement::HighAddresses);
+        let high: Vec<NonNull<u8>> = [8, 64, 8]
+            .into_iter()
+            .map(|size| alloc.alloc_bytes(size).unwrap())
+            .collect();
+        assert_eq!(high.iter().map(|&ptr| offset(ptr)).collect::<Vec<_>>(), [1016, 896, 1008]);
+
+        // Per-call placements override the allocator's policy
+        let low = alloc.alloc_bytes_placed(8, Placement::LowAddresses).unwrap();
+        assert_eq!(offset(low), 0);
+
+        alloc.set_placement(Placement::LowAddresses);
+        assert_eq!(offset(alloc.alloc_bytes(16).unwrap()), 16);
+        assert_eq!(offset(alloc.alloc_bytes_placed(128, Placement::HighAddresses).unwrap()), 768);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_zones() {
+
+        const DMA: usize = 0;
+        const NORMAL: usize = 1;
+        const HIGH: usize = 2;
+
+        let dma = BuddyAllocator::<256, 8>::new(false);
+        let normal = BuddyAllocator::<1024, 16>::new(false);
+        let high = BuddyAllocator::<4096, 64>::new(false);
+
+        let mut zones = ZonedAllocator::new([&*dma as &dyn Zone, &*normal, &*high]);
+        zones.set_fallback_order([HIGH, NORMAL, DMA]);
+
+        // Requests go to the first allowed zone in the fallback order
+        let (ptr, zone) = zones.alloc_bytes(100, ZonedAllocator::<3>::ALL_ZONES).unwrap();
+        assert_eq!(zone, HIGH);
+        let (dma_ptr, zone) = zones.alloc_bytes(100, 1 << DMA).unwrap();
+        assert_eq!(zone, DMA);
+
+        // Zones that run out of memory fall back to the next allowed one
+        let (big, zone) = zones.alloc_bytes(1024, 1 << NORMAL | 1 << DMA).unwrap();
+        assert_eq!(zone, NORMAL);
+        assert!(matches!(zones.alloc_bytes(512, 1 << NORMAL | 1 << DMA), Err(AllocError::OutOfMemory)));
+        assert!(matches!(zones.alloc_bytes(8, 0), Err(AllocError::OutOfMemory)));
+
+        // Frees are routed to the zone that owns the block
+        assert_eq!(zones.zone_of(dma_ptr), Some(DMA));
+        for ptr in [ptr, dma_ptr, big] {
+            assert!(zones.free_nonnull(ptr).is_ok());
+        }
+        assert!(matches!(zones.free_nonnull(NonNull::<u8>::dangling()), Err(FreeError::FreeOutOfBounds)));
+        assert_eq!(zones.total_free(ZonedAllocator::<3>::ALL_ZONES), 256 + 1024 + 4096);
+    }
+
+
+    #[test]
+    fn check_waiting_allocations() {
+
+        use std::{future::Future, sync::Arc, task::{Context, Poll, Wake, Waker}, thread, time::Duration};
+
+        struct Flag(std::sync::atomic::AtomicBool);
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+        let woken = |flag: &Flag| flag.0.swap(false, std::sync::atomic::Ordering::SeqCst);
+
+        let alloc = SyncBuddyAllocator::<1024, 8>::new(false);
+        let full = alloc.alloc_bytes(1024).unwrap();
+
+        // Requests larger than the heap fail right away, short waits time out
+        assert!(matches!(pin!(alloc.alloc_async(2048)).poll(&mut Context::from_waker(Waker::noop())), Poll::Ready(Err(AllocError::OutOfMemory))));
+        assert!(matches!(alloc.alloc_wait(8, Some(Duration::from_millis(1))), Err(AllocError::OutOfMemory)));
+
+        let first_flag = Arc::new(Flag(false.into()));
+        let second_flag = Arc::new(Flag(false.into()));
+        let first_waker = Waker::from(first_flag.clone());
+        let second_waker = Waker::from(second_flag.clone());
+
+        let mut first = pin!(alloc.alloc_async(512));
+        let mut second = pin!(alloc.alloc_async(8));
+        assert!(first.as_mut().poll(&mut Context::from_waker(&first_waker)).is_pending());
+        assert!(second.as_mut().poll(&mut Context::from_waker(&second_waker)).is_pending());
+
+        // Freeing wakes the oldest waiter, which is served first
+        assert!(alloc.free_nonnull(full).is_ok());
+        assert!(woken(&first_flag));
+        assert!(!woken(&second_flag));
+        assert!(second.as_mut().poll(&mut Context::from_waker(&second_waker)).is_pending());
+        let Poll::Ready(Ok(a)) = first.as_mut().poll(&mut Context::from_waker(&first_waker)) else { panic!() };
+        assert!(woken(&second_flag));
+        let Poll::Ready(Ok(b)) = second.as_mut().poll(&mut Context::from_waker(&second_waker)) else { panic!() };
+
+        // A blocked thread wakes up once another thread frees enough memory
+        let c = alloc.alloc_bytes(256).unwrap();
+        let d = alloc.alloc_bytes(128).unwrap();
+        assert_eq!(alloc.total_free(), 1024 - 512 - 256 - 128 - 8);
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| alloc.alloc_wait(512, None).map(|ptr| ptr.addr()));
+            thread::sleep(Duration::from_millis(10));
+            for ptr in [b, c, d] {
+                assert!(alloc.free_nonnull(ptr).is_ok());
+            }
+            assert!(waiter.join().unwrap().is_ok());
+        });
+        assert!(alloc.free_nonnull(a).is_ok());
+        assert_eq!(alloc.total_free(), 512);
+        alloc.with(|alloc| assert_eq!(alloc.check_integrity(), Ok(())));
+    }
+
+
+    #[test]
+    fn check_waiting_for_reserved_memory() {
+
+        use std::{future::Future, task::{Context, Poll, Waker}};
+
+        let alloc = SyncBuddyAllocator::<1024, 8>::new(false);
+        let mut cx = Context::from_waker(Waker::noop());
+        alloc.with(|alloc| alloc.reserve_range(256, 8)).unwrap();
+
+        // Requests that no amount of freeing can serve don't wait
+        assert!(matches!(alloc.alloc_wait(1024, None), Err(AllocError::OutOfMemory)));
+        assert!(matches!(pin!(alloc.alloc_async(1024)).poll(&mut cx), Poll::Ready(Err(AllocError::OutOfMemory))));
+
+        let high = alloc.alloc_bytes(512).unwrap();
+        let low = alloc.alloc_bytes(256).unwrap();
+        let mut first = pin!(alloc.alloc_async(512));
+        let mut second = pin!(alloc.alloc_async(8));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        // Reserving memory while a request waits fails it instead of blocking the queue
+        alloc.with(|alloc| {
+            alloc.free_nonnull(high).unwrap();
+            alloc.reserve_range(768, 8).unwrap();
+        });
+        assert!(matches!(first.as_mut().poll(&mut cx), Poll::Ready(Err(AllocError::OutOfMemory))));
+        let Poll::Ready(Ok(ptr)) = second.as_mut().poll(&mut cx) else { panic!() };
+
+        for ptr in [low, ptr] {
+            assert!(alloc.free_nonnull(ptr).is_ok());
+        }
+        assert_eq!(alloc.total_free(), 1024 - 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "polled after completion")]
+    fn check_alloc_future_polled_after_completion() {
+
+        use std::{future::Future, task::{Context, Waker}};
+
+        let alloc = SyncBuddyAllocator::<1024, 8>::new(false);
+        let mut future = pin!(alloc.alloc_async(8));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(future.as_mut().poll(&mut cx).is_ready());
+
+        // Polling again must not allocate a second block
+        let _ = future.as_mut().poll(&mut cx);
+    }
+
+    #[test]
+    fn check_memory_pressure() {
+
+        use std::sync::{Arc, Mutex};
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        alloc.set_pressure_handler((256, 512), Some(Box::new(move |_, pressure| recorded.lock().unwrap().push(pressure))));
+        assert_eq!(alloc.watermarks(), Some((256, 512)));
+
+        // A cache the out-of-memory handler can shrink
+        let cache = Arc::new(Mutex::new(Vec::new()));
+        let shrunk = cache.clone();
+        alloc.set_oom_handler(Some(Box::new(move |alloc, _| match shrunk.lock().unwrap().pop() {
+            Some(handle) => {
+                assert!(alloc.free_handle(handle).is_ok());
+                OomAction::Retry
+            },
+            None => OomAction::Fail
+        })));
+
+        let a = alloc.alloc_handle(512).unwrap();
+        cache.lock().unwrap().push(alloc.alloc_handle(256).unwrap());
+        assert!(events.lock().unwrap().is_empty());
+
+        // Dropping below the low watermark fires once
+        let b = alloc.alloc_handle(128).unwrap();
+        assert_eq!(*events.lock().unwrap(), [MemoryPressure::Low]);
+
+        // The handler frees the cache so that the allocation succeeds, then gives up once the cache is empty
+        let c = alloc.alloc_handle(256).unwrap();
+        assert!(cache.lock().unwrap().is_empty());
+        assert!(matches!(alloc.alloc_bytes(256), Err(AllocError::OutOfMemory)));
+        assert_eq!(*events.lock().unwrap(), [MemoryPressure::Low]);
+
+        // Rising back to the high watermark fires once
+        for handle in [b, c] {
+            assert!(alloc.free_handle(handle).is_ok());
+        }
+        assert_eq!(*events.lock().unwrap(), [MemoryPressure::Low, MemoryPressure::Relieved]);
+        assert!(alloc.free_handle(a).is_ok());
+        assert_eq!(events.lock().unwrap().len(), 2);
+
+        alloc.set_pressure_handler((0, 0), None);
+        assert_eq!(alloc.watermarks(), None);
+        assert_eq!(alloc.check_integrity(), Ok(()));
+    }
+
+
+    #[test]
+    fn check_handlers_replacing_themselves() {
+
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        let full = alloc.alloc_bytes(1024).unwrap();
+
+        // An out-of-memory handler that removes itself is not put back
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        alloc.set_oom_handler(Some(Box::new(move |alloc, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            alloc.set_oom_handler(None);
+            OomAction::Fail
+        })));
+        assert!(matches!(alloc.alloc_bytes(8), Err(AllocError::OutOfMemory)));
+        assert!(matches!(alloc.alloc_bytes(8), Err(AllocError::OutOfMemory)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // An out-of-memory handler that replaces itself keeps its replacement
+        let replacement_calls = Arc::new(AtomicUsize::new(0));
+        let (counted, replacement_counted) = (calls.clone(), replacement_calls.clone());
+        alloc.set_oom_handler(Some(Box::new(move |alloc, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let replacement_counted = replacement_counted.clone();
+            alloc.set_oom_handler(Some(Box::new(move |_, _| {
+                replacement_counted.fetch_add(1, Ordering::SeqCst);
+                OomAction::Fail
+            })));
+            OomAction::Fail
+        })));
+        assert!(matches!(alloc.alloc_bytes(8), Err(AllocError::OutOfMemory)));
+        assert!(matches!(alloc.alloc_bytes(8), Err(AllocError::OutOfMemory)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(replacement_calls.load(Ordering::SeqCst), 1);
+        assert!(alloc.free_nonnull(full).is_ok());
+
+        // A pressure handler that removes itself is not put back
+        let counted = calls.clone();
+        alloc.set_pressure_handler((512, 512), Some(Box::new(move |alloc, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            alloc.set_pressure_handler((0, 0), None);
+        })));
+        let a = alloc.alloc_bytes(1024).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(alloc.watermarks(), None);
+
+        // A pressure handler that replaces itself keeps its replacement
+        let counted = calls.clone();
+        let replacement_counted = replacement_calls.clone();
+        alloc.set_pressure_handler((512, 512), Some(Box::new(move |alloc, pressure| {
+            assert_eq!(pressure, MemoryPressure::Relieved);
+            counted.fetch_add(1, Ordering::SeqCst);
+            let replacement_counted = replacement_counted.clone();
+            alloc.set_pressure_handler((256, 256), Some(Box::new(move |_, _| {
+                replacement_counted.fetch_add(1, Ordering::SeqCst);
+            })));
+        })));
+        assert!(alloc.free_nonnull(a).is_ok());
+        assert_eq!(alloc.watermarks(), Some((256, 256)));
+        let _b = alloc.alloc_bytes(1024).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(replacement_calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[test]
+    fn check_handles() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        let a = alloc.alloc_handle(16).unwrap();
+        let b = alloc.alloc_handle(16).unwrap();
+
+        assert_eq!(a.offset(), 0);
+        assert_eq!(b.offset(), 16);
+
+        let ptr = alloc.resolve(b);
+        assert_eq!(alloc.handle_of(ptr), Some(b));
+
+        assert!(matches!(alloc.free_handle(BlockHandle::from_offset(1024)), Err(FreeError::FreeOutOfBounds)));
+        assert!(matches!(alloc.free_handle(BlockHandle::from_offset(4)), Err(FreeError::UnalignedFree)));
+
+        assert!(alloc.free_handle(a).is_ok());
+        assert!(matches!(alloc.free_handle(a), Err(FreeError::DoubleFree)));
+        assert!(alloc.free_nonnull(ptr).is_ok());
+
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+    }
+
+
+    #[test]
+    fn check_snapshot_restore() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(true);
+
+        let a = alloc.alloc_handle(16).unwrap();
+        let b = alloc.alloc_handle(100).unwrap();
+        alloc.alloc_handle(8).unwrap();
+        alloc.free_handle(a).unwrap();
+
+        unsafe {
+            alloc.resolve(b).write_bytes(0x5A, 100);
+        }
+
+        let snapshot = unsafe { alloc.snapshot_with_heap() };
+
+        let restored = BuddyAllocator::<1024, 8>::new(false);
+        assert!(unsafe { restored.restore(&snapshot) }.is_ok());
+
+        assert_eq!(restored.total_free(), alloc.total_free());
+        assert_eq!(restored.snapshot(), alloc.snapshot());
+        assert_eq!(unsafe { *restored.resolve(b).as_ptr().add(99) }, 0x5A);
+
+        // The restored allocator keeps working from the restored state
+        assert!(matches!(restored.free_handle(a), Err(FreeError::DoubleFree)));
+        assert!(restored.free_handle(b).is_ok());
+
+        let mut corrupted = snapshot.clone();
+        corrupted[50] ^= 1;
+        assert!(matches!(unsafe { restored.restore(&corrupted) }, Err(SnapshotError::ChecksumMismatch)));
+        assert!(matches!(unsafe { restored.restore(&snapshot[..20]) }, Err(SnapshotError::Truncated)));
+
+        let other = BuddyAllocator::<2048, 8>::new(false);
+        assert!(matches!(unsafe { other.restore(&snapshot) }, Err(SnapshotError::GeometryMismatch)));
+    }
+
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri cannot map files into memory")]
+    fn check_persistent_heap() {
+
+        let path = std::env::temp_dir().join(format!("buddy_allocator_persistent_{}", std::process::id()));
+
+        let mut heap = PersistentHeap::<1024, 8>::create(&path).unwrap();
+
+        let a = heap.alloc_handle(16).unwrap();
+        let b = heap.alloc_handle(100).unwrap();
+        unsafe {
+            heap.resolve(b).write_bytes(0x5A, 100);
+        }
+        heap.free_handle(a).unwrap();
+        heap.close().unwrap();
+
+        // Reopen a cleanly shut down heap
+        let mut heap = PersistentHeap::<1024, 8>::open(&path).unwrap();
+        assert_eq!(heap.recovery_report(), None);
+        assert_eq!(heap.total_allocated(), 128);
+        assert_eq!(unsafe { *heap.resolve(b).as_ptr().add(99) }, 0x5A);
+        assert!(matches!(heap.free_handle(a), Err(FreeError::DoubleFree)));
+
+        let c = heap.alloc_handle(8).unwrap();
+
+        // Simulate a crash by never shutting the heap down
+        std::mem::forget(heap);
+
+        let mut heap = PersistentHeap::<1024, 8>::open(&path).unwrap();
+        assert_eq!(heap.recovery_report(), Some(RecoveryReport { restored_blocks: 2, discarded_entries: 0 }));
+        assert!(heap.free_handle(b).is_ok());
+        assert!(heap.free_handle(c).is_ok());
+        assert_eq!(heap.total_free(), heap.heap_size());
+        heap.close().unwrap();
+
+        assert!(matches!(PersistentHeap::<2048, 8>::open(&path), Err(PersistentHeapError::GeometryMismatch)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri cannot map files into memory")]
+    fn check_large_persistent_heap_small_stack() {
+
+        let path = std::env::temp_dir().join(format!("buddy_allocator_persistent_large_{}", std::process::id()));
+
+        // The allocation tree of a 4 MiB heap is much larger than the stack of the worker
+        let worker = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn({
+                let path = path.clone();
+                move || {
+                    let mut heap = PersistentHeap::<4194304, 64>::create(&path).unwrap();
+                    let handle = heap.alloc_handle(1 << 20).unwrap();
+                    heap.close().unwrap();
+
+                    let mut heap = PersistentHeap::<4194304, 64>::open(&path).unwrap();
+                    let freed = heap.free_handle(handle).is_ok();
+                    heap.close().unwrap();
+                    freed
+                }
+            })
+            .unwrap();
+
+        assert!(worker.join().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri cannot fork or map shared memory")]
+    fn check_shared_heap() {
+
+        const CHILDREN: usize = 4;
+        const BLOCKS: usize = 32;
+
+        let name = format!("buddy_allocator_shared_{}", std::process::id());
+
+        let mut heap = SharedHeap::<65536, 64>::create(&name).unwrap();
+        assert!(matches!(SharedHeap::<65536, 64>::create(&name), Err(SharedHeapError::Io(_))));
+
+        let pids: Vec<i32> = (0..CHILDREN).map(|child| {
+
+            let pid = unsafe { libc::fork() };
+            if pid != 0 {
+                return pid;
+            }
+
+            // Each child allocates blocks concurrently, tags them and checks nobody else wrote to them.
+            let mut heap = SharedHeap::<65536, 64>::open(&name).unwrap();
+            let tag = child as u8 + 1;
+
+            let handles: Vec<BlockHandle> = (0..BLOCKS).map(|_| {
+                let handle = heap.alloc_handle(64).unwrap();
+                unsafe { heap.resolve(handle).write_bytes(tag, 64) };
+                handle
+            }).collect();
+
+            let intact = handles.iter().all(|&handle| {
+                (0..64).all(|i| unsafe { *heap.resolve(handle).as_ptr().add(i) } == tag)
+            });
+
+            // Keep half of the blocks allocated
+            let freed = handles.iter().step_by(2).all(|&handle| heap.free_handle(handle).is_ok());
+
+            unsafe { libc::_exit(if intact && freed { 0 } else { 1 }) }
+        }).collect();
+
+        for pid in pids {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert_eq!(status, 0);
+        }
+
+        assert_eq!(heap.total_allocated().unwrap(), CHILDREN * BLOCKS / 2 * 64);
+
+        // Blocks allocated by the children can be freed by the parent
+        let mut freed = 0;
+        for offset in (0..heap.heap_size()).step_by(64) {
+            if heap.free_handle(BlockHandle::from_offset(offset)).is_ok() {
+                freed += 1;
+            }
+        }
+        assert_eq!(freed, CHILDREN * BLOCKS / 2);
+        assert_eq!(heap.total_free().unwrap(), heap.heap_size());
+        assert!(matches!(heap.free_handle(BlockHandle::from_offset(0)), Err(SharedHeapError::Free(FreeError::DoubleFree))));
+
+        SharedHeap::<65536, 64>::unlink(&name).unwrap();
+    }
+
+
+    #[cfg(feature = "debug-heap")]
+    #[test]
+    fn check_debug_heap() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        let ptr = alloc.alloc_bytes(12).unwrap();
+        unsafe {
+            assert_eq!(*ptr.as_ptr(), ALLOC_PATTERN);
+            assert_eq!(*ptr.as_ptr().add(11), ALLOC_PATTERN);
+            assert_eq!(*ptr.as_ptr().add(12), RED_ZONE_PATTERN);
+            assert_eq!(*ptr.as_ptr().add(15), RED_ZONE_PATTERN);
+        }
+
+        assert!(alloc.free_nonnull(ptr).is_ok());
+        assert_eq!(unsafe { *ptr.as_ptr() }, FREE_PATTERN);
+
+        // Writing past the requested size is caught when the block is freed
+        let ptr = alloc.alloc_bytes(12).unwrap();
+        unsafe {
+            ptr.as_ptr().add(12).write(0);
+        }
+        assert!(matches!(alloc.free_nonnull(ptr), Err(FreeError::BufferOverflow)));
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+    }
+
+
+    #[cfg(feature = "debug-heap")]
+    #[test]
+    fn check_quarantine() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        alloc.set_quarantine(Some(QuarantinePolicy::Frees(2))).unwrap();
+
+        let a = alloc.alloc_handle(8).unwrap();
+        let b = alloc.alloc_handle(8).unwrap();
+        let c = alloc.alloc_handle(8).unwrap();
+
+        // Quarantined blocks are neither free nor reused
+        alloc.free_handle(a).unwrap();
+        assert_eq!(alloc.total_quarantined(), 8);
+        assert_eq!(alloc.total_free(), alloc.heap_size() - 24);
+        assert!(matches!(alloc.free_handle(a), Err(FreeError::DoubleFree)));
+        assert!(matches!(alloc.free_nonnull(unsafe { alloc.resolve(a).add(4) }), Err(FreeError::UnalignedFree)));
+        assert_ne!(alloc.alloc_handle(8).unwrap(), a);
+
+        // Write through a dangling pointer
+        unsafe {
+            alloc.resolve(a).write(0);
+        }
+
+        alloc.free_handle(b).unwrap();
+        assert!(matches!(alloc.free_handle(c), Err(FreeError::UseAfterFree(handle)) if handle == a));
+        assert_eq!(alloc.total_quarantined(), 16);
+
+        assert!(alloc.flush_quarantine().is_ok());
+        assert_eq!(alloc.total_quarantined(), 0);
+        assert_eq!(alloc.total_free(), alloc.heap_size() - 8);
+    }
+
+
+    #[cfg(feature = "debug-heap")]
+    #[test]
+    fn check_quarantine_snapshot() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        alloc.set_quarantine(Some(QuarantinePolicy::Frees(4))).unwrap();
+
+        let handle = alloc.alloc_handle(8).unwrap();
+        alloc.free_handle(handle).unwrap();
+
+        // Quarantined blocks have been freed, so the snapshot records them as free
+        let snapshot = alloc.snapshot();
+        assert_eq!(alloc.total_quarantined(), 0);
+
+        unsafe {
+            alloc.restore(&snapshot).unwrap();
+        }
+
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert!(alloc.leak_report().is_empty());
+    }
+
+
+    #[test]
+    fn check_leak_report() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        let a = alloc.alloc_handle(16).unwrap();
+        let b = alloc.alloc_handle(5).unwrap();
+        let c = alloc.alloc_handle(64).unwrap();
+        alloc.free_handle(a).unwrap();
+
+        let leaks = alloc.leak_report();
+        assert_eq!(leaks.iter().map(|leak| (leak.handle, leak.size)).collect::<Vec<_>>(), [(b, 8), (c, 64)]);
+        assert_eq!(leaks[0].address, alloc.resolve(b));
+
+        #[cfg(feature = "debug-heap")]
+        assert_eq!(leaks[0].requested_size, Some(5));
+
+        alloc.free_handle(b).unwrap();
+        alloc.free_handle(c).unwrap();
+        assert!(alloc.leak_report().is_empty());
+    }
+
+
+    #[test]
+    #[should_panic(expected = "leaked blocks")]
+    fn check_leak_check_on_drop() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+        alloc.set_leak_check(LeakCheck::Panic);
+
+        alloc.alloc_handle(16).unwrap();
+    }
+
+
+    #[cfg(feature = "track-allocations")]
+    #[test]
+    fn check_allocation_tracking() {
+
+        let alloc = BuddyAllocator::<1024, 8>::new(false);
+
+        let (ptr, alloc_line) = (alloc.alloc_bytes(16).unwrap(), line!());
+
+        let leaks = alloc.leak_report();
+        assert_eq!(leaks[0].allocated_at.as_ref().unwrap().location.line(), alloc_line);
+
+        let (res, free_line) = (alloc.free_nonnull(ptr), line!());
+        assert!(res.is_ok());
+        assert!(matches!(alloc.free_nonnull(ptr), Err(FreeError::DoubleFree)));
+
+        // Find out who allocated the block and who freed it first
+        let info = alloc.allocation_info(alloc.handle_of(ptr).unwrap()).unwrap();
+        assert_eq!(info.allocated_at.location.file(), file!());
+        assert_eq!(info.allocated_at.location.line(), alloc_line);
+        assert_eq!(info.freed_at.as_ref().unwrap().location.line(), free_line);
+        assert!(info.allocated_at.backtrace.is_none());
+
+        alloc.set_capture_backtraces(true);
+        let handle = alloc.alloc_handle(16).unwrap();
+        assert!(alloc.allocation_info(handle).unwrap().allocated_at.backtrace.is_some());
+    }
+
+
+    #[test]
+    fn check_integrity_under_churn() {
+
+        let alloc = BuddyAllocator::<4096, 8>::new(false);
+        let mut handles = Vec::new();
+
+        // Deterministic pseudo-random sequence of allocations and frees
+        let mut seed: u32 = 0x2545F491;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+
+        for _ in 0..1000 {
+
+            if handles.len() < 32 && next() % 3 != 0 {
+                if let Ok(handle) = alloc.alloc_handle(next() % 200 + 1) {
+                    handles.push(handle);
+                }
+            } else if !handles.is_empty() {
+                let handle = handles.swap_remove(next() % handles.len());
+                alloc.free_handle(handle).unwrap();
+            }
+
+            assert_eq!(alloc.check_integrity(), Ok(()));
+        }
+
+        for handle in handles {
+            alloc.free_handle(handle).unwrap();
+        }
+
+        assert_eq!(alloc.check_integrity(), Ok(()));
+        assert_eq!(alloc.total_free(), alloc.heap_size());
+    }
 
 
     #[test]
@@ -112,9 +1275,9 @@ mod tests {
             alloc.as_mut().init_pinned()
         }
 
-        assert!(matches!(alloc.as_mut().alloc_bytes(0), Err(AllocError::ZeroAllocation)));
+        assert!(matches!(alloc.alloc_bytes(0), Err(AllocError::ZeroAllocation)));
 
-        assert!(matches!(alloc.as_mut().alloc_bytes(1025), Err(AllocError::OutOfMemory)));
+        assert!(matches!(alloc.alloc_bytes(1025), Err(AllocError::OutOfMemory)));
     }
 
 
@@ -127,13 +1290,13 @@ mod tests {
         unsafe {
             alloc.as_mut().init_pinned()
         }
-        assert!(alloc.as_mut().alloc_bytes(1).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(8).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(9).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(24).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(32).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(65).is_ok());
-        assert!(alloc.as_mut().alloc_bytes(1000).is_err());
+        assert!(alloc.alloc_bytes(1).is_ok());
+        assert!(alloc.alloc_bytes(8).is_ok());
+        assert!(alloc.alloc_bytes(9).is_ok());
+        assert!(alloc.alloc_bytes(24).is_ok());
+        assert!(alloc.alloc_bytes(32).is_ok());
+        assert!(alloc.alloc_bytes(65).is_ok());
+        assert!(alloc.alloc_bytes(1000).is_err());
     }
 
 
@@ -146,8 +1309,8 @@ mod tests {
         unsafe {
             alloc.as_mut().init_pinned()
         }
-        assert!(matches!(alloc.as_mut().free(ptr::null() as *const u8), Err(FreeError::NullPtrFree)));
-        assert!(matches!(alloc.as_mut().free(usize::MAX as *const u8), Err(FreeError::FreeOutOfBounds)));
+        assert!(matches!(alloc.free(ptr::null::<u8>()), Err(FreeError::NullPtrFree)));
+        assert!(matches!(alloc.free(usize::MAX as *const u8), Err(FreeError::FreeOutOfBounds)));
     }
 
 
@@ -165,14 +1328,15 @@ mod tests {
         ];
 
         let ptrs: Vec<NonNull<u8>> = blocks.iter()
-            .map(|&s| alloc.as_mut().alloc_bytes(s as usize).unwrap())
+            .map(|&s| alloc.alloc_bytes(s as usize).unwrap())
             .collect();
 
         for ptr in ptrs {
-            assert!(alloc.as_mut().free_nonnull(ptr).is_ok());
+            assert!(alloc.free_nonnull(ptr).is_ok());
         }
 
         assert_eq!(alloc.total_free(), alloc.heap_size());
+        assert!(alloc.leak_report().is_empty());
     }   
 
 }
@@ -0,0 +1,182 @@
+/// Maximum number of orders that can have a magazine.
+pub const MAX_MAGAZINE_ORDERS: usize = 16;
+
+/// Link of a block that isn't cached.
+const NOT_CACHED: usize = usize::MAX;
+
+/// Link of the last block of a magazine.
+const LAST: usize = usize::MAX - 1;
+
+
+/// Configuration of the per-order caches of freed blocks, called magazines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MagazineConfig {
+
+    /// Number of orders, starting from zero-order blocks, that get a magazine.
+    /// Values above `MAX_MAGAZINE_ORDERS` or above the order of the whole heap are clamped.
+    pub orders: usize,
+
+    /// Maximum number of blocks each magazine holds.
+    pub capacity: usize,
+
+}
+
+
+/// Bounded LIFO caches of freed blocks of the smallest orders.
+/// Cached blocks stay allocated in the allocation tree, so they can be handed out again without touching it.
+pub(crate) struct Magazines<const B: usize, const N: usize> {
+
+    config: Option<MagazineConfig>,
+
+    /// Offset of the most recently cached block of each order. Only meaningful if the magazine isn't empty.
+    heads: [usize; MAX_MAGAZINE_ORDERS],
+
+    /// Number of blocks held in the magazine of each order.
+    counts: [usize; MAX_MAGAZINE_ORDERS],
+
+    /// Offset of the next block in the same magazine, indexed by zero-order block.
+    /// `LAST` for the last block of a magazine and `NOT_CACHED` for blocks that aren't cached, so that cached blocks can be told apart in constant time.
+    links: [usize; N],
+
+    /// Total size of the cached blocks.
+    bytes: usize,
+
+}
+
+impl<const B: usize, const N: usize> Magazines<B, N> {
+
+    pub const fn new() -> Self {
+        Self {
+            config: None,
+            heads: [0; MAX_MAGAZINE_ORDERS],
+            counts: [0; MAX_MAGAZINE_ORDERS],
+            links: [NOT_CACHED; N],
+            bytes: 0
+        }
+    }
+
+
+    /// Initialize empty magazines at `this` without building them on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    pub unsafe fn init_in_place(this: *mut Self) {
+        unsafe {
+            (&raw mut (*this).config).write(None);
+            (&raw mut (*this).heads).write([0; MAX_MAGAZINE_ORDERS]);
+            (&raw mut (*this).counts).write([0; MAX_MAGAZINE_ORDERS]);
+            (&raw mut (*this).bytes).write(0);
+
+            // Write the links one by one, so that the array is never built on the stack
+            let links = (&raw mut (*this).links).cast::<usize>();
+            for i in 0..N {
+                links.add(i).write(NOT_CACHED);
+            }
+        }
+    }
+
+
+    pub const fn config(&self) -> Option<MagazineConfig> {
+        self.config
+    }
+
+
+    /// Change the configuration of the magazines.
+    /// Assume they are empty.
+    pub fn set_config(&mut self, config: Option<MagazineConfig>) {
+        debug_assert_eq!(self.bytes, 0);
+        self.config = config;
+    }
+
+
+    /// Return the order of the blocks that serve allocations of `size` bytes.
+    pub const fn order_of(size: usize) -> usize {
+        size.div_ceil(B).next_power_of_two().trailing_zeros() as usize
+    }
+
+
+    /// Return whether blocks of the given order have a magazine.
+    const fn has_magazine(&self, order: usize) -> bool {
+        match self.config {
+            Some(config) => order < config.orders && order < MAX_MAGAZINE_ORDERS && B << order <= B * N,
+            None => false
+        }
+    }
+
+
+    /// Cache the block of `size` bytes at `offset`.
+    /// Return whether the block was cached, which fails if its order has no magazine or the magazine is full.
+    pub fn push(&mut self, offset: usize, size: usize) -> bool {
+
+        let order = Self::order_of(size);
+
+        if !self.has_magazine(order) || self.counts[order] == self.config.unwrap().capacity {
+            return false;
+        }
+
+        self.links[offset / B] = if self.counts[order] > 0 { self.heads[order] } else { LAST };
+        self.heads[order] = offset;
+        self.counts[order] += 1;
+        self.bytes += size;
+
+        true
+    }
+
+
+    /// Take the most recently cached block of the given order out of its magazine and return its offset.
+    pub fn pop(&mut self, order: usize) -> Option<usize> {
+
+        if !self.has_magazine(order) || self.counts[order] == 0 {
+            return None;
+        }
+
+        let offset = self.heads[order];
+
+        self.counts[order] -= 1;
+        self.heads[order] = self.links[offset / B];
+        self.links[offset / B] = NOT_CACHED;
+        self.bytes -= B << order;
+
+        Some(offset)
+    }
+
+
+    /// Take any cached block out of its magazine and return its offset and size.
+    pub fn pop_any(&mut self) -> Option<(usize, usize)> {
+
+        let order = self.counts.iter().position(|&count| count > 0)?;
+        self.pop(order)
+            .map(|offset| (offset, B << order))
+    }
+
+
+    /// Return whether the block at `offset` is cached.
+    /// Offsets inside a cached block, rather than at its start, are not reported as cached.
+    pub fn contains(&self, offset: usize) -> bool {
+        offset.is_multiple_of(B) && self.links[offset / B] != NOT_CACHED
+    }
+
+
+    /// Return the total size of the cached blocks.
+    pub const fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+
+    /// Forget every cached block.
+    pub fn clear(&mut self) {
+
+        for order in 0..MAX_MAGAZINE_ORDERS {
+
+            let mut cached = self.heads[order];
+            for _ in 0..self.counts[order] {
+                cached = std::mem::replace(&mut self.links[cached / B], NOT_CACHED);
+            }
+        }
+
+        self.counts = [0; MAX_MAGAZINE_ORDERS];
+        self.bytes = 0;
+    }
+
+}
@@ -0,0 +1,287 @@
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{errors::{AllocError, FreeError, PersistentHeapError}, handle::BlockHandle, region::{Region, RecoveryReport, PAGE_SIZE}, sys};
+
+
+/*
+    The backing file contains a region (see `region.rs`) whose header page stores a `Header`.
+    The allocation map makes the heap crash-consistent: the clean-shutdown flag only decides whether the map is trusted as-is
+    or whether a recovery scan has to validate it and discard inconsistent entries.
+*/
+
+
+const MAGIC: [u8; 8] = *b"BDYHEAP\0";
+const VERSION: u32 = 1;
+
+/// The heap was shut down cleanly.
+const CLEAN: u32 = 1;
+/// The heap is open, or its process crashed before shutting it down.
+const DIRTY: u32 = 0;
+
+
+/// Header stored at the start of the backing file.
+#[repr(C)]
+struct Header {
+
+    magic: [u8; 8],
+
+    version: u32,
+
+    /// Whether the heap was shut down cleanly.
+    shutdown_state: u32,
+
+    heap_size: u64,
+
+    block_size: u64,
+
+}
+
+
+/**
+    A buddy allocator whose heap and allocation metadata live in a memory-mapped file.
+
+    Allocations are addressed through position-independent `BlockHandle`s, so a process can reopen the file and find its allocations still there,
+    even if the file gets mapped at a different address.
+    The heap has a size of `M` bytes and a zero-order block size of `B` bytes, with the same constraints as `BuddyAllocator`.
+
+    Heap blocks are aligned to their size or to the page size, whichever is smaller.
+*/
+pub struct PersistentHeap<const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    /// The heap laid out in the memory-mapped backing file.
+    region: Region<M, B>,
+
+    /// The outcome of the recovery scan, if one was needed when opening the heap.
+    recovery: Option<RecoveryReport>,
+
+    /// Whether the heap has already been shut down and unmapped.
+    closed: bool,
+
+}
+
+impl<const M: usize, const B: usize> PersistentHeap<M, B>
+where
+    [(); M / B]:
+{
+
+    fn header(&self) -> *mut Header {
+        self.region.base().as_ptr() as *mut Header
+    }
+
+
+    /// Write the state of the heap back to the backing file.
+    /// Changes to a shared mapping already survive a crash of the process, but they are only durable across a system crash once flushed.
+    pub fn flush(&self) -> Result<(), PersistentHeapError> {
+        sys::sync(self.region.base(), Region::<M, B>::SIZE)
+            .map_err(PersistentHeapError::from)
+    }
+
+
+    /// Flush the heap, mark it as cleanly shut down and unmap it.
+    fn shutdown(&mut self) -> Result<(), PersistentHeapError> {
+
+        if self.closed {
+            return Ok(());
+        }
+
+        self.flush()?;
+
+        // The shutdown flag is only set once everything else has reached the file
+        unsafe {
+            (*self.header()).shutdown_state = CLEAN;
+        }
+        sys::sync(self.region.base(), PAGE_SIZE)?;
+
+        self.closed = true;
+        sys::unmap(self.region.base(), Region::<M, B>::SIZE)
+            .map_err(PersistentHeapError::from)
+    }
+
+}
+
+impl<const M: usize, const B: usize> PersistentHeap<M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Create a new, empty heap backed by the file at `path`.
+    /// If the file already exists, it's overwritten.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, PersistentHeapError> {
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+
+        // The file is zero-filled, which is an empty allocation map.
+        file.set_len(Region::<M, B>::SIZE as u64)?;
+
+        let heap = Self::with_mapping(sys::map_shared(file.as_raw_fd(), Region::<M, B>::SIZE)?);
+
+        unsafe {
+            heap.header().write(Header {
+                magic: MAGIC,
+                version: VERSION,
+                shutdown_state: DIRTY,
+                heap_size: M as u64,
+                block_size: B as u64
+            });
+        }
+        sys::sync(heap.region.base(), PAGE_SIZE)?;
+
+        Ok(heap)
+    }
+
+
+    /// Open an existing heap backed by the file at `path`.
+    /// If the heap was not shut down cleanly, a recovery scan validates the allocation map and discards inconsistent entries.
+    /// Its outcome is available through `recovery_report()`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PersistentHeapError> {
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        // Read the header before mapping the file, so that a heap of another size is reported as such
+        let mut header = [0u8; mem::size_of::<Header>()];
+        file.read_exact(&mut header)
+            .map_err(|_| PersistentHeapError::InvalidHeader)?;
+        // Every bit pattern is a valid header
+        Self::validate(&unsafe { header.as_ptr().cast::<Header>().read_unaligned() })?;
+
+        if file.metadata()?.len() != Region::<M, B>::SIZE as u64 {
+            return Err(PersistentHeapError::InvalidHeader);
+        }
+
+        let mut heap = Self::with_mapping(sys::map_shared(file.as_raw_fd(), Region::<M, B>::SIZE)?);
+
+        if let Err(e) = heap.load() {
+            // Leave the file untouched
+            heap.closed = true;
+            sys::unmap(heap.region.base(), Region::<M, B>::SIZE)?;
+            return Err(e);
+        }
+
+        Ok(heap)
+    }
+
+
+    fn with_mapping(mapping: NonNull<u8>) -> Self {
+        Self {
+            region: Region::new(mapping),
+            recovery: None,
+            closed: false
+        }
+    }
+
+
+    /// Check that `header` belongs to a heap of this version and geometry.
+    fn validate(header: &Header) -> Result<(), PersistentHeapError> {
+
+        if header.magic != MAGIC || header.version != VERSION {
+            return Err(PersistentHeapError::InvalidHeader);
+        }
+
+        if header.heap_size != M as u64 || header.block_size != B as u64 {
+            return Err(PersistentHeapError::GeometryMismatch);
+        }
+
+        Ok(())
+    }
+
+
+    /// Validate the header and rebuild the allocation tree from the allocation map.
+    fn load(&mut self) -> Result<(), PersistentHeapError> {
+
+        let header = unsafe { &*self.header() };
+        Self::validate(header)?;
+
+        if header.shutdown_state == CLEAN {
+            self.region.rebuild(false)
+                .ok_or(PersistentHeapError::CorruptedMetadata)?;
+        } else {
+            self.recovery = self.region.rebuild(true);
+        }
+
+        // Until the heap is shut down cleanly, a crash must trigger a recovery scan.
+        unsafe {
+            (*self.header()).shutdown_state = DIRTY;
+        }
+        sys::sync(self.region.base(), PAGE_SIZE)?;
+
+        Ok(())
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes.
+    /// Return a position-independent handle to the allocated block, which stays valid across reopens of the heap.
+    pub fn alloc_handle(&mut self, size: usize) -> Result<BlockHandle, AllocError> {
+        self.region.alloc_handle(size)
+    }
+
+
+    /// Free the memory block referenced by `handle`.
+    pub fn free_handle(&mut self, handle: BlockHandle) -> Result<(), FreeError> {
+        self.region.free_handle(handle)
+    }
+
+
+    /// Return a pointer to the memory block referenced by `handle`.
+    /// The pointer is only valid until the heap is closed.
+    pub fn resolve(&self, handle: BlockHandle) -> NonNull<u8> {
+        self.region.resolve(handle)
+    }
+
+
+    /// Return the outcome of the recovery scan, or `None` if the heap was shut down cleanly the last time it was used.
+    pub const fn recovery_report(&self) -> Option<RecoveryReport> {
+        self.recovery
+    }
+
+
+    /// Return the total amount of free memory in the heap.
+    /// Note that this memory may not be usable as a whole because of fragmentation.
+    pub const fn total_free(&self) -> usize {
+        self.region.total_free()
+    }
+
+
+    /// Return the total size of the heap.
+    pub const fn heap_size(&self) -> usize {
+        M
+    }
+
+
+    /// Return the size of allocated memory. That is, the amount of memory that is currently in use.
+    pub const fn total_allocated(&self) -> usize {
+        self.heap_size() - self.total_free()
+    }
+
+
+    /// Flush the heap, mark it as cleanly shut down and unmap it.
+    /// Dropping the heap does the same, but ignores errors.
+    pub fn close(mut self) -> Result<(), PersistentHeapError> {
+        self.shutdown()
+    }
+
+}
+
+impl<const M: usize, const B: usize> Drop for PersistentHeap<M, B>
+where
+    [(); M / B]:
+{
+
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+
+}
+
@@ -0,0 +1,13 @@
+/// Which end of the heap allocations are packed toward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+
+    /// Prefer the free block with the lowest address, trying left buddies first.
+    #[default]
+    LowAddresses,
+
+    /// Prefer the free block with the highest address, trying right buddies first.
+    /// Sending long-lived allocations to one end of the heap and short-lived ones to the other reduces fragmentation.
+    HighAddresses,
+
+}
@@ -0,0 +1,100 @@
+/// What an allocator should do after its out-of-memory handler returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+
+    /// Try the allocation again, because the handler freed some memory.
+    Retry,
+
+    /// Give up and return `AllocError::OutOfMemory`.
+    Fail,
+
+}
+
+
+/// A change in memory pressure, reported when the free memory crosses a watermark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPressure {
+
+    /// The free memory dropped below the low watermark.
+    Low,
+
+    /// The free memory rose back to the high watermark after being low.
+    Relieved,
+
+}
+
+
+/// Called with the allocator and the requested size when an allocation is about to fail for lack of memory.
+pub type OomHandler<A> = Box<dyn FnMut(&A, usize) -> OomAction + Send>;
+
+/// Called with the allocator when the free memory crosses a watermark.
+pub type PressureHandler<A> = Box<dyn FnMut(&A, MemoryPressure) + Send>;
+
+
+/// Watches the free memory of an allocator `A` and reports when it crosses the watermarks.
+pub(crate) struct PressureWatch<A> {
+
+    low: usize,
+
+    high: usize,
+
+    /// Whether the free memory dropped below the low watermark and hasn't risen back to the high watermark yet.
+    under_pressure: bool,
+
+    /// Taken out while it runs, so that it doesn't run again for changes it causes itself.
+    handler: Option<PressureHandler<A>>,
+
+}
+
+impl<A> PressureWatch<A> {
+
+    /// Start watching the free memory, which currently amounts to `total_free` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `low` is greater than `high`.
+    pub fn new(low: usize, high: usize, total_free: usize, handler: PressureHandler<A>) -> Self {
+
+        assert!(low <= high, "The low watermark must not be greater than the high watermark");
+
+        Self {
+            low,
+            high,
+            under_pressure: total_free < low,
+            handler: Some(handler)
+        }
+    }
+
+
+    /// Return the low and high watermarks.
+    pub const fn watermarks(&self) -> (usize, usize) {
+        (self.low, self.high)
+    }
+
+
+    /// Update the pressure with the current amount of free memory.
+    /// If it crossed a watermark and the handler is not already running, return the change along with the handler, which must be given back through `restore()`.
+    pub fn update(&mut self, total_free: usize) -> Option<(MemoryPressure, PressureHandler<A>)> {
+
+        // Changes caused by the handler are reported once it has returned
+        self.handler.as_ref()?;
+
+        let pressure = if !self.under_pressure && total_free < self.low {
+            MemoryPressure::Low
+        } else if self.under_pressure && total_free >= self.high {
+            MemoryPressure::Relieved
+        } else {
+            return None;
+        };
+
+        self.under_pressure = pressure == MemoryPressure::Low;
+        self.handler.take().map(|handler| (pressure, handler))
+    }
+
+
+    /// Give back the handler returned by `update()`.
+    pub fn restore(&mut self, handler: PressureHandler<A>) {
+        self.handler = Some(handler);
+    }
+
+}
@@ -0,0 +1,219 @@
+use std::ptr::NonNull;
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{alloc_table::AllocTable, errors::{AllocError, FreeError}, handle::BlockHandle};
+
+
+/*
+    Region layout:
+
+    | header: one page, owned by the backend | allocation map: M / B bytes, padded to a page boundary | heap: M bytes |
+
+    The allocation map is the source of truth shared by everyone who maps the region. It holds one byte per zero-order block:
+    `0` if no allocated block starts there, or `order + 1` if an allocated block of `B * 2^order` bytes starts there.
+    Every allocation and free updates a single map byte, so the map is consistent at any point in time.
+    The allocation tree only lives in the memory of the process and is rebuilt from the map when needed.
+*/
+
+
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+
+/// Summary of a scan that rebuilt the allocation tree from a region's allocation map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryReport {
+
+    /// Number of allocated blocks found in the allocation map and restored.
+    pub restored_blocks: usize,
+
+    /// Number of invalid or overlapping allocation map entries that were discarded.
+    pub discarded_entries: usize,
+
+}
+
+
+/// A buddy heap laid out in an externally provided memory region and addressed through offsets.
+/// Heap blocks are aligned to their size or to the page size, whichever is smaller, as long as the region is page-aligned.
+pub(crate) struct Region<const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    /// Start of the memory region.
+    base: NonNull<u8>,
+
+    /// A binary tree that keeps track of the allocated and free blocks.
+    alloc_table: Box<AllocTable<B, {M / B}>>,
+
+    /// The total amount of free memory, which may not be available as a whole due to fragmentation.
+    total_free: usize,
+
+}
+
+impl<const M: usize, const B: usize> Region<M, B>
+where
+    [(); M / B]:
+{
+
+    /// Offset of the allocation map in the region.
+    const MAP_OFFSET: usize = PAGE_SIZE;
+
+    /// Offset of the heap in the region.
+    const HEAP_OFFSET: usize = (Self::MAP_OFFSET + M / B).next_multiple_of(PAGE_SIZE);
+
+    /// Total size of the region.
+    pub const SIZE: usize = Self::HEAP_OFFSET + M;
+
+
+    /// Return the start of the region, where the backend header is stored.
+    pub const fn base(&self) -> NonNull<u8> {
+        self.base
+    }
+
+
+    /// Return the allocation map entry of the zero-order block at `index`.
+    fn map_entry(&self, index: usize) -> *mut u8 {
+        unsafe {
+            self.base.as_ptr().add(Self::MAP_OFFSET + index)
+        }
+    }
+
+}
+
+impl<const M: usize, const B: usize> Region<M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Create an empty heap on top of the region that starts at `base`.
+    /// The allocation map is not touched. Call `rebuild()` to load it.
+    /// Assume `base` points to `Self::SIZE` valid bytes.
+    pub fn new(base: NonNull<u8>) -> Self {
+
+        // The allocation tree may be too large for the stack, so build it directly on the heap
+        let mut alloc_table = Box::<AllocTable<B, { M / B }>>::new_uninit();
+
+        Self {
+            base,
+            alloc_table: unsafe {
+                AllocTable::init_in_place(alloc_table.as_mut_ptr());
+                alloc_table.assume_init()
+            },
+            total_free: M
+        }
+    }
+
+
+    /// Discard the allocation tree and rebuild it from the allocation map.
+    /// Every map entry must describe a block that fits in the heap, is aligned to its size and doesn't overlap other blocks.
+    /// If `repair` is set, invalid entries are cleared from the map. Otherwise, the first invalid entry aborts the scan and `None` is returned.
+    pub fn rebuild(&mut self, repair: bool) -> Option<RecoveryReport> {
+
+        self.alloc_table.clear();
+        self.total_free = M;
+
+        let max_order = (M / B).trailing_zeros();
+
+        let mut report = RecoveryReport {
+            restored_blocks: 0,
+            discarded_entries: 0
+        };
+
+        for index in 0..M / B {
+
+            let entry = unsafe { *self.map_entry(index) };
+            if entry == 0 {
+                continue;
+            }
+
+            let order = (entry - 1) as u32;
+            let offset = index * B;
+
+            let valid = order <= max_order && offset.is_multiple_of(B << order) && {
+                let size = B << order;
+                let claimed = self.alloc_table.claim(offset, size);
+                if claimed {
+                    self.total_free -= size;
+                }
+                claimed
+            };
+
+            if valid {
+                report.restored_blocks += 1;
+            } else if repair {
+                unsafe {
+                    *self.map_entry(index) = 0;
+                }
+                report.discarded_entries += 1;
+            } else {
+                return None;
+            }
+        }
+
+        Some(report)
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes and record it in the allocation map.
+    pub fn alloc_handle(&mut self, size: usize) -> Result<BlockHandle, AllocError> {
+
+        if size == 0 {
+            Err(AllocError::ZeroAllocation)
+
+        } else if size > self.total_free {
+            Err(AllocError::OutOfMemory)
+
+        } else if let Some((offset, allocated)) = self.alloc_table.alloc(size) {
+
+            // Record the allocation before handing it out
+            unsafe {
+                *self.map_entry(offset / B) = (allocated / B).trailing_zeros() as u8 + 1;
+            }
+
+            self.total_free -= allocated;
+            Ok(BlockHandle::from_offset(offset))
+
+        } else {
+            Err(AllocError::OutOfMemory)
+        }
+    }
+
+
+    /// Free the memory block referenced by `handle` and remove it from the allocation map.
+    pub fn free_handle(&mut self, handle: BlockHandle) -> Result<(), FreeError> {
+
+        if handle.offset() >= M {
+            return Err(FreeError::FreeOutOfBounds);
+        }
+
+        let freed = self.alloc_table.free(handle.offset())?;
+
+        unsafe {
+            *self.map_entry(handle.offset() / B) = 0;
+        }
+
+        self.total_free += freed;
+        Ok(())
+    }
+
+
+    /// Return a pointer to the memory block referenced by `handle`.
+    pub fn resolve(&self, handle: BlockHandle) -> NonNull<u8> {
+        debug_assert!(handle.offset() < M, "Block handle out of the heap bounds");
+        unsafe {
+            self.base.byte_add(Self::HEAP_OFFSET + handle.offset())
+        }
+    }
+
+
+    /// Return the total amount of free memory in the heap.
+    pub const fn total_free(&self) -> usize {
+        self.total_free
+    }
+
+}
+
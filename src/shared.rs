@@ -0,0 +1,269 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{errors::SharedHeapError, handle::BlockHandle, region::Region, sys::{self, RawMutex}};
+
+
+/*
+    The shared memory segment contains a region (see `region.rs`) whose header page stores a `Header`.
+    Every process keeps its own copy of the allocation tree, rebuilt from the shared allocation map whenever
+    another process has changed the map since the last time the tree was synchronized.
+*/
+
+
+const MAGIC: [u8; 8] = *b"BDYSHM\0\0";
+const VERSION: u32 = 2;
+
+/// Directory where Linux exposes POSIX shared memory objects.
+const SHM_DIR: &str = "/dev/shm";
+
+
+/// Header stored at the start of the shared memory segment.
+#[repr(C)]
+struct Header {
+
+    magic: [u8; 8],
+
+    version: u32,
+
+    /// Set once the creator has fully initialized the segment.
+    ready: AtomicU32,
+
+    heap_size: u64,
+
+    block_size: u64,
+
+    /// Incremented every time the allocation map changes. Protected by `lock`.
+    generation: u64,
+
+    /// Process-shared lock that serializes all operations on the heap.
+    lock: RawMutex,
+
+}
+
+
+/**
+    A buddy allocator whose heap lives in a POSIX shared memory segment, so that cooperating processes can allocate and free blocks for each other.
+
+    Allocations are addressed through position-independent `BlockHandle`s, since every process may map the segment at a different address.
+    All operations are serialized by a robust, process-shared lock stored in the segment. If a process dies while holding the lock, the next process
+    to acquire it rebuilds the allocation state from the allocation map.
+    The heap has a size of `M` bytes and a zero-order block size of `B` bytes, with the same constraints as `BuddyAllocator`.
+
+    Each process keeps a local copy of the allocation tree, which is rebuilt in `O(M / B)` time whenever another process has changed the heap.
+*/
+pub struct SharedHeap<const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    /// The heap laid out in the memory-mapped shared memory segment.
+    region: Region<M, B>,
+
+    /// Generation of the allocation map the local allocation tree reflects.
+    generation: u64,
+
+}
+
+impl<const M: usize, const B: usize> SharedHeap<M, B>
+where
+    [(); M / B]:
+{
+
+    fn header(&self) -> *mut Header {
+        self.region.base().as_ptr() as *mut Header
+    }
+
+}
+
+impl<const M: usize, const B: usize> SharedHeap<M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Create a new, empty heap in the shared memory segment called `name`.
+    /// Fail if a segment with the same name already exists.
+    pub fn create(name: &str) -> Result<Self, SharedHeapError> {
+
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(Self::segment_path(name)?)?;
+
+        // The segment is zero-filled, which is an empty allocation map.
+        file.set_len(Region::<M, B>::SIZE as u64)?;
+
+        let heap = Self::with_mapping(sys::map_shared(file.as_raw_fd(), Region::<M, B>::SIZE)?);
+
+        unsafe {
+            let header = heap.header();
+
+            (&raw mut (*header).magic).write(MAGIC);
+            (&raw mut (*header).version).write(VERSION);
+            (&raw mut (*header).heap_size).write(M as u64);
+            (&raw mut (*header).block_size).write(B as u64);
+            (&raw mut (*header).generation).write(0);
+            sys::init_shared_mutex(&raw mut (*header).lock)?;
+
+            // Publish the segment to the other processes
+            (*header).ready.store(1, Ordering::Release);
+        }
+
+        Ok(heap)
+    }
+
+
+    /// Open the existing heap in the shared memory segment called `name`.
+    pub fn open(name: &str) -> Result<Self, SharedHeapError> {
+
+        let file = OpenOptions::new().read(true).write(true).open(Self::segment_path(name)?)?;
+
+        if file.metadata()?.len() != Region::<M, B>::SIZE as u64 {
+            return Err(SharedHeapError::InvalidHeader);
+        }
+
+        let heap = Self::with_mapping(sys::map_shared(file.as_raw_fd(), Region::<M, B>::SIZE)?);
+
+        let header = unsafe { &*heap.header() };
+
+        if header.ready.load(Ordering::Acquire) != 1 || header.magic != MAGIC || header.version != VERSION {
+            return Err(SharedHeapError::InvalidHeader);
+        }
+
+        if header.heap_size != M as u64 || header.block_size != B as u64 {
+            return Err(SharedHeapError::GeometryMismatch);
+        }
+
+        Ok(heap)
+    }
+
+
+    /// Remove the shared memory segment called `name`.
+    /// Processes that have already opened the heap can keep using it until they drop it.
+    pub fn unlink(name: &str) -> Result<(), SharedHeapError> {
+        fs::remove_file(Self::segment_path(name)?)
+            .map_err(SharedHeapError::from)
+    }
+
+
+    fn segment_path(name: &str) -> Result<PathBuf, SharedHeapError> {
+
+        if name.is_empty() || name.contains('/') {
+            Err(SharedHeapError::Io(io::ErrorKind::InvalidInput))
+        } else {
+            Ok(PathBuf::from(SHM_DIR).join(name))
+        }
+    }
+
+
+    fn with_mapping(mapping: NonNull<u8>) -> Self {
+        Self {
+            region: Region::new(mapping),
+            // Force the allocation tree to be built on first use
+            generation: u64::MAX
+        }
+    }
+
+
+    /// Run `operation` on the heap while holding the process-shared lock, after bringing the local allocation tree up to date.
+    /// `operation` returns whether it changed the allocation map.
+    fn locked<R>(&mut self, operation: impl FnOnce(&mut Region<M, B>) -> (R, bool)) -> Result<R, SharedHeapError> {
+
+        let header = self.header();
+
+        let owner_died = unsafe { sys::lock_shared_mutex(&raw mut (*header).lock) }
+            .map_err(|error| SharedHeapError::LockFailed(error.kind()))?;
+
+        unsafe {
+
+            if owner_died {
+                // The dead process may have changed the allocation map without publishing it. Make everyone rebuild their tree.
+                (*header).generation = (*header).generation.wrapping_add(1);
+            }
+
+            if (*header).generation != self.generation {
+                self.region.rebuild(true);
+            }
+        }
+
+        let (res, changed) = operation(&mut self.region);
+
+        unsafe {
+
+            if changed {
+                (*header).generation = (*header).generation.wrapping_add(1);
+            }
+            self.generation = (*header).generation;
+
+            sys::unlock_shared_mutex(&raw mut (*header).lock)
+                .map_err(|error| SharedHeapError::LockFailed(error.kind()))?;
+        }
+
+        Ok(res)
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes.
+    /// Return a position-independent handle to the allocated block, which any process that opened the heap can resolve.
+    pub fn alloc_handle(&mut self, size: usize) -> Result<BlockHandle, SharedHeapError> {
+        self.locked(|region| {
+            let res = region.alloc_handle(size);
+            let changed = res.is_ok();
+            (res, changed)
+        })?.map_err(SharedHeapError::from)
+    }
+
+
+    /// Free the memory block referenced by `handle`, regardless of which process allocated it.
+    pub fn free_handle(&mut self, handle: BlockHandle) -> Result<(), SharedHeapError> {
+        self.locked(|region| {
+            let res = region.free_handle(handle);
+            let changed = res.is_ok();
+            (res, changed)
+        })?.map_err(SharedHeapError::from)
+    }
+
+
+    /// Return a pointer to the memory block referenced by `handle` in the address space of the calling process.
+    pub fn resolve(&self, handle: BlockHandle) -> NonNull<u8> {
+        self.region.resolve(handle)
+    }
+
+
+    /// Return the total amount of free memory in the heap, as seen by all processes.
+    /// Note that this memory may not be usable as a whole because of fragmentation.
+    pub fn total_free(&mut self) -> Result<usize, SharedHeapError> {
+        self.locked(|region| (region.total_free(), false))
+    }
+
+
+    /// Return the total size of the heap.
+    pub const fn heap_size(&self) -> usize {
+        M
+    }
+
+
+    /// Return the size of allocated memory, as seen by all processes.
+    pub fn total_allocated(&mut self) -> Result<usize, SharedHeapError> {
+        Ok(self.heap_size() - self.total_free()?)
+    }
+
+}
+
+impl<const M: usize, const B: usize> Drop for SharedHeap<M, B>
+where
+    [(); M / B]:
+{
+
+    fn drop(&mut self) {
+        let _ = sys::unmap(self.region.base(), Region::<M, B>::SIZE);
+    }
+
+}
+
@@ -0,0 +1,380 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::NonNull;
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{buddy_allocator::BuddyAllocator, errors::{AllocError, FreeError}, handle::BlockHandle};
+
+
+/*
+    Every slab is a buddy block of `slab_size` bytes. It starts with a `SlabHeader`, followed by a bitmap of the allocated slots, and is then carved into equal slots.
+    Free slots hold a pointer to the next free slot of the same slab, so the free list needs no memory of its own.
+    The bitmap makes double frees detectable in constant time, since a freed slot would otherwise be linked twice.
+    Buddy blocks are aligned to their size relative to the heap base, so the slab an object belongs to is found by rounding its offset down.
+*/
+
+
+/// Bookkeeping stored at the start of every slab.
+struct SlabHeader {
+
+    /// Neighbours in the list of partial or full slabs the slab belongs to.
+    prev: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+
+    /// First free slot, which stores the address of the next one.
+    free: Option<NonNull<u8>>,
+
+    /// Number of allocated objects.
+    in_use: usize,
+
+    /// Number of slots in the slab.
+    capacity: usize,
+
+    /// Start of the first slot.
+    objects: NonNull<u8>,
+
+}
+
+
+/**
+    A cache of objects of type `T` carved out of slabs, which are blocks allocated from a `BuddyAllocator`.
+
+    Objects much smaller than the zero-order block size `B` would waste most of a buddy block each,
+    while a small `B` makes the allocation tree huge. A slab cache instead packs many objects in a single block,
+    and returns each slab to the allocator as soon as none of its objects is allocated anymore.
+
+    Objects are not initialized nor dropped by the cache.
+    Dropping the cache returns every slab to the allocator, which invalidates the objects still allocated from it.
+*/
+pub struct SlabCache<'a, T, const M: usize, const B: usize>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// The allocator the slabs are taken from.
+    alloc: &'a BuddyAllocator<M, B>,
+
+    /// Size of every slab in bytes.
+    slab_size: usize,
+
+    /// Slabs with at least one free and one allocated slot.
+    partial: Option<NonNull<SlabHeader>>,
+
+    /// Slabs with no free slot.
+    full: Option<NonNull<SlabHeader>>,
+
+    /// Number of slabs currently held.
+    slabs: usize,
+
+    /// Number of objects currently allocated.
+    in_use: usize,
+
+    /// Bitmap of the slabs of the heap that belong to the cache, indexed by their offset divided by the slab size.
+    /// Frees of pointers into other blocks are rejected without reading them.
+    owned: Box<[usize]>,
+
+    _marker: PhantomData<*mut T>,
+
+}
+
+impl<'a, T, const M: usize, const B: usize> SlabCache<'a, T, M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Alignment of every slot, which must fit both a `T` and the free list link.
+    const SLOT_ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<usize>() { mem::align_of::<T>() } else { mem::align_of::<usize>() };
+
+    /// Size of every slot.
+    const SLOT_SIZE: usize = if mem::size_of::<T>() > mem::size_of::<usize>() { mem::size_of::<T>() } else { mem::size_of::<usize>() }
+        .next_multiple_of(Self::SLOT_ALIGN);
+
+    /// Number of bits in a bitmap word.
+    const WORD_BITS: usize = usize::BITS as usize;
+
+
+    /// Create an empty cache whose slabs are blocks of `slab_size` bytes taken from `alloc`.
+    /// The slab size is rounded up to a power of two no smaller than `B`. No slab is allocated until the first object is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a slab of the rounded size cannot hold at least one object, or doesn't fit in the heap.
+    pub fn new(alloc: &'a BuddyAllocator<M, B>, slab_size: usize) -> Self {
+
+        let slab_size = slab_size.max(B).next_power_of_two();
+
+        // The heap base may not be aligned, so leave room for aligning the header and the first slot
+        let overhead = mem::size_of::<SlabHeader>() + mem::align_of::<SlabHeader>() - 1 + Self::bitmap_words(slab_size) * mem::size_of::<usize>() + Self::SLOT_ALIGN - 1;
+        assert!(slab_size <= M, "The slab size exceeds the heap size");
+        assert!(overhead + Self::SLOT_SIZE <= slab_size, "The slab size is too small to hold an object");
+
+        Self {
+            alloc,
+            slab_size,
+            partial: None,
+            full: None,
+            slabs: 0,
+            in_use: 0,
+            owned: vec![0; (M / slab_size).div_ceil(Self::WORD_BITS)].into_boxed_slice(),
+            _marker: PhantomData
+        }
+    }
+
+
+    /// Return the size of every slab in bytes.
+    pub const fn slab_size(&self) -> usize {
+        self.slab_size
+    }
+
+
+    /// Return the number of slabs currently taken from the allocator.
+    pub const fn slab_count(&self) -> usize {
+        self.slabs
+    }
+
+
+    /// Return the number of objects currently allocated.
+    pub const fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+
+    /// Allocate memory for an object of type `T`.
+    /// A new slab is taken from the allocator if every slab is full.
+    /// The returned memory is not initialized.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc(&mut self) -> Result<NonNull<T>, AllocError> {
+
+        let mut slab = match self.partial {
+            Some(slab) => slab,
+            None => self.new_slab()?
+        };
+
+        let header = unsafe { slab.as_mut() };
+
+        // Slabs in the partial list always have a free slot
+        let slot = header.free.unwrap();
+        header.free = unsafe { slot.cast::<Option<NonNull<u8>>>().read() };
+        header.in_use += 1;
+
+        let index = (slot.as_ptr().addr() - header.objects.as_ptr().addr()) / Self::SLOT_SIZE;
+        unsafe {
+            *Self::bitmap_of(slab).add(index / Self::WORD_BITS).as_mut() |= 1 << (index % Self::WORD_BITS);
+        }
+        self.in_use += 1;
+
+        if header.free.is_none() {
+            unsafe {
+                Self::unlink(&mut self.partial, slab);
+                Self::push(&mut self.full, slab);
+            }
+        }
+
+        Ok(slot.cast())
+    }
+
+
+    /// Return the object at `ptr` to its slab.
+    /// The slab is returned to the allocator if none of its objects is allocated anymore.
+    /// Fail with `FreeError::UnalignedFree` if `ptr` doesn't point to a slot of this cache, or with `FreeError::DoubleFree` if the slot is already free.
+    /// Note that the object is not dropped.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn free(&mut self, ptr: NonNull<T>) -> Result<(), FreeError> {
+
+        let handle = self.alloc.handle_of(ptr).ok_or(FreeError::FreeOutOfBounds)?;
+
+        // Only slabs of this cache hold a valid header
+        let slab_index = handle.offset() / self.slab_size;
+        if self.owned[slab_index / Self::WORD_BITS] & (1 << (slab_index % Self::WORD_BITS)) == 0 {
+            return Err(FreeError::UnalignedFree);
+        }
+
+        let slab_handle = BlockHandle::from_offset(slab_index * self.slab_size);
+        let mut slab = Self::header_of(self.alloc.resolve(slab_handle));
+        let header = unsafe { slab.as_mut() };
+
+        let address = ptr.as_ptr().addr();
+        let objects = header.objects.as_ptr().addr();
+
+        if address < objects || !(address - objects).is_multiple_of(Self::SLOT_SIZE) || (address - objects) / Self::SLOT_SIZE >= header.capacity {
+            return Err(FreeError::UnalignedFree);
+        }
+
+        let index = (address - objects) / Self::SLOT_SIZE;
+        let mut word = unsafe { Self::bitmap_of(slab).add(index / Self::WORD_BITS) };
+        let word = unsafe { word.as_mut() };
+        if *word & (1 << (index % Self::WORD_BITS)) == 0 {
+            return Err(FreeError::DoubleFree);
+        }
+        *word &= !(1 << (index % Self::WORD_BITS));
+
+        let was_full = header.free.is_none();
+
+        unsafe {
+            ptr.cast::<Option<NonNull<u8>>>().write(header.free);
+        }
+        header.free = Some(ptr.cast());
+        header.in_use -= 1;
+        self.in_use -= 1;
+
+        let list = if was_full { &mut self.full } else { &mut self.partial };
+
+        if header.in_use == 0 {
+            unsafe {
+                Self::unlink(list, slab);
+            }
+            self.slabs -= 1;
+            self.owned[slab_index / Self::WORD_BITS] &= !(1 << (slab_index % Self::WORD_BITS));
+            return self.alloc.free_handle(slab_handle);
+        }
+
+        if was_full {
+            unsafe {
+                Self::unlink(&mut self.full, slab);
+                Self::push(&mut self.partial, slab);
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// Return the number of bitmap words needed to track every slot a slab of `slab_size` bytes may hold.
+    const fn bitmap_words(slab_size: usize) -> usize {
+        (slab_size / Self::SLOT_SIZE).div_ceil(Self::WORD_BITS)
+    }
+
+
+    /// Return the bitmap of the allocated slots of `slab`, which follows its header.
+    fn bitmap_of(slab: NonNull<SlabHeader>) -> NonNull<usize> {
+        // The header is at least as aligned as a `usize`
+        unsafe { slab.add(1).cast() }
+    }
+
+
+    /// Return the header of the slab that starts at `block`.
+    fn header_of(block: NonNull<u8>) -> NonNull<SlabHeader> {
+        unsafe {
+            block.byte_add(block.align_offset(mem::align_of::<SlabHeader>())).cast()
+        }
+    }
+
+
+    /// Take a new slab from the allocator, carve it into free slots and add it to the partial list.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn new_slab(&mut self) -> Result<NonNull<SlabHeader>, AllocError> {
+
+        let block_handle = self.alloc.alloc_handle(self.slab_size)?;
+        let block = self.alloc.resolve(block_handle);
+        let slab = Self::header_of(block);
+
+        let words = Self::bitmap_words(self.slab_size);
+        let bitmap = Self::bitmap_of(slab);
+
+        let objects = unsafe {
+            bitmap.write_bytes(0, words);
+            let after_bitmap = bitmap.add(words).cast::<u8>();
+            after_bitmap.byte_add(after_bitmap.align_offset(Self::SLOT_ALIGN))
+        };
+        let capacity = (block.as_ptr().addr() + self.slab_size - objects.as_ptr().addr()) / Self::SLOT_SIZE;
+
+        // Link the slots in address order, so that objects are handed out from the start of the slab
+        let mut free = None;
+        for i in (0..capacity).rev() {
+            unsafe {
+                let slot = objects.byte_add(i * Self::SLOT_SIZE);
+                slot.cast::<Option<NonNull<u8>>>().write(free);
+                free = Some(slot);
+            }
+        }
+
+        unsafe {
+            slab.write(SlabHeader {
+                prev: None,
+                next: None,
+                free,
+                in_use: 0,
+                capacity,
+                objects
+            });
+            Self::push(&mut self.partial, slab);
+        }
+
+        let slab_index = block_handle.offset() / self.slab_size;
+        self.owned[slab_index / Self::WORD_BITS] |= 1 << (slab_index % Self::WORD_BITS);
+
+        self.slabs += 1;
+        Ok(slab)
+    }
+
+
+    /// Insert `slab` at the head of `list`.
+    ///
+    /// # Safety
+    ///
+    /// `slab` must be a valid slab that doesn't belong to any list.
+    unsafe fn push(list: &mut Option<NonNull<SlabHeader>>, mut slab: NonNull<SlabHeader>) {
+        unsafe {
+            slab.as_mut().prev = None;
+            slab.as_mut().next = *list;
+            if let Some(mut head) = *list {
+                head.as_mut().prev = Some(slab);
+            }
+        }
+        *list = Some(slab);
+    }
+
+
+    /// Remove `slab` from `list`.
+    ///
+    /// # Safety
+    ///
+    /// `slab` must be a valid slab that belongs to `list`.
+    unsafe fn unlink(list: &mut Option<NonNull<SlabHeader>>, slab: NonNull<SlabHeader>) {
+        unsafe {
+            let SlabHeader { prev, next, .. } = *slab.as_ptr();
+            match prev {
+                Some(mut prev) => prev.as_mut().next = next,
+                None => *list = next
+            }
+            if let Some(mut next) = next {
+                next.as_mut().prev = prev;
+            }
+        }
+    }
+
+}
+
+impl<T, const M: usize, const B: usize> Drop for SlabCache<'_, T, M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    fn drop(&mut self) {
+
+        for list in [self.partial, self.full] {
+
+            let mut slab = list;
+
+            while let Some(current) = slab {
+                slab = unsafe { current.as_ref().next };
+
+                // Slabs are allocated blocks of the allocator, and errors cannot be reported from a destructor anyway
+                let handle = self.alloc.handle_of(current).unwrap();
+                let block = BlockHandle::from_offset(handle.offset() & !(self.slab_size - 1));
+                let _ = self.alloc.free_handle(block);
+            }
+        }
+    }
+
+}
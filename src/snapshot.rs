@@ -0,0 +1,161 @@
+use crate::errors::SnapshotError;
+
+
+/*
+    Snapshot format (all integers are little-endian):
+
+    | magic: [u8; 4] | version: u16 | flags: u16 | heap size: u64 | block size: u64 | total free: u64 | tree length: u64 |
+    | tree: [u8; tree length] | heap contents: [u8; heap size] (only if the heap flag is set) | checksum: u32 |
+
+    The tree is encoded in pre-order, one tag byte per node.
+    The checksum is a 32-bit FNV-1a hash of all the preceding bytes.
+*/
+
+
+/// Tag of a free leaf node in the encoded allocation tree.
+pub(crate) const FREE_LEAF_TAG: u8 = 0;
+/// Tag of an allocated leaf node in the encoded allocation tree.
+pub(crate) const ALLOCATED_LEAF_TAG: u8 = 1;
+/// Tag of a split node in the encoded allocation tree. It's followed by the encodings of its left and right children.
+pub(crate) const PARENT_TAG: u8 = 2;
+/// Tag of a reserved leaf node in the encoded allocation tree.
+pub(crate) const RESERVED_LEAF_TAG: u8 = 3;
+
+const MAGIC: [u8; 4] = *b"BDSN";
+const VERSION: u16 = 1;
+
+/// The snapshot includes the contents of the heap.
+const FLAG_HEAP_CONTENTS: u16 = 1;
+
+const HEADER_SIZE: usize = 4 + 2 + 2 + 8 * 4;
+const CHECKSUM_SIZE: usize = 4;
+
+
+/// The validated contents of a snapshot.
+pub(crate) struct ParsedSnapshot<'s> {
+
+    /// The amount of free memory recorded in the snapshot.
+    pub total_free: usize,
+
+    /// The pre-order encoding of the allocation tree.
+    pub tree: &'s [u8],
+
+    /// The heap contents, if they were included in the snapshot.
+    pub heap: Option<&'s [u8]>,
+
+}
+
+
+/// Serialize the allocator state into the snapshot format.
+pub(crate) fn write_snapshot(heap_size: usize, block_size: usize, total_free: usize, tree: &[u8], heap: Option<&[u8]>) -> Vec<u8> {
+
+    let mut data = Vec::with_capacity(HEADER_SIZE + tree.len() + heap.map_or(0, <[u8]>::len) + CHECKSUM_SIZE);
+
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&(if heap.is_some() { FLAG_HEAP_CONTENTS } else { 0 }).to_le_bytes());
+    data.extend_from_slice(&(heap_size as u64).to_le_bytes());
+    data.extend_from_slice(&(block_size as u64).to_le_bytes());
+    data.extend_from_slice(&(total_free as u64).to_le_bytes());
+    data.extend_from_slice(&(tree.len() as u64).to_le_bytes());
+    data.extend_from_slice(tree);
+
+    if let Some(heap) = heap {
+        data.extend_from_slice(heap);
+    }
+
+    let checksum = checksum(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+
+    data
+}
+
+
+/// Parse and validate a snapshot taken from an allocator with a heap of `heap_size` bytes and zero-order blocks of `block_size` bytes.
+/// `max_nodes` is the maximum number of tree nodes, excluding the root, that the target allocator can store.
+pub(crate) fn parse_snapshot(data: &[u8], heap_size: usize, block_size: usize, max_nodes: usize) -> Result<ParsedSnapshot<'_>, SnapshotError> {
+
+    if data.len() < HEADER_SIZE + CHECKSUM_SIZE {
+        return Err(SnapshotError::Truncated);
+    }
+
+    if data[0..4] != MAGIC {
+        return Err(SnapshotError::InvalidMagic);
+    }
+
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let (body, stored_checksum) = data.split_at(data.len() - CHECKSUM_SIZE);
+    if checksum(body) != u32::from_le_bytes(stored_checksum.try_into().unwrap()) {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let flags = u16::from_le_bytes([data[6], data[7]]);
+    let read_u64 = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+
+    if read_u64(8) != heap_size as u64 || read_u64(16) != block_size as u64 {
+        return Err(SnapshotError::GeometryMismatch);
+    }
+
+    let total_free = read_u64(24);
+    let tree_len = read_u64(32);
+
+    let heap_len = if flags & FLAG_HEAP_CONTENTS != 0 { heap_size as u64 } else { 0 };
+    if (body.len() - HEADER_SIZE) as u64 != tree_len.saturating_add(heap_len) {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let (tree, heap) = body[HEADER_SIZE..].split_at(tree_len as usize);
+
+    // Check the tree is well-formed before anything gets rebuilt from it
+    let mut encoding = tree;
+    let mut nodes = 0;
+    let free = validate_tree(&mut encoding, heap_size, block_size, &mut nodes)?;
+
+    if !encoding.is_empty() || nodes > max_nodes || free as u64 != total_free {
+        return Err(SnapshotError::CorruptedTree);
+    }
+
+    Ok(ParsedSnapshot {
+        total_free: free,
+        tree,
+        heap: if heap.is_empty() { None } else { Some(heap) }
+    })
+}
+
+
+/// Recursively check the pre-order encoding of a subtree whose root block is `size` bytes large.
+/// Consume the subtree encoding, count its nodes (excluding the root) and return the amount of free memory it describes.
+fn validate_tree(encoding: &mut &[u8], size: usize, block_size: usize, nodes: &mut usize) -> Result<usize, SnapshotError> {
+
+    let (&tag, rest) = encoding.split_first().ok_or(SnapshotError::CorruptedTree)?;
+    *encoding = rest;
+
+    match tag {
+
+        FREE_LEAF_TAG => Ok(size),
+
+        ALLOCATED_LEAF_TAG | RESERVED_LEAF_TAG => Ok(0),
+
+        // A zero-order block cannot be split
+        PARENT_TAG if size > block_size => {
+            *nodes += 2;
+            let left = validate_tree(encoding, size / 2, block_size, nodes)?;
+            let right = validate_tree(encoding, size / 2, block_size, nodes)?;
+            Ok(left + right)
+        },
+
+        _ => Err(SnapshotError::CorruptedTree)
+    }
+}
+
+
+/// Compute the 32-bit FNV-1a hash of `data`.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
@@ -0,0 +1,121 @@
+use std::hint;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use const_assert::{Assert, IsTrue};
+
+use crate::buddy_allocator::BuddyAllocator;
+
+
+/**
+    A `BuddyAllocator` guarded by a spin lock, meant to be stored in a plain `static` variable.
+
+    `BuddyAllocator` is not `Sync`, so it cannot be shared through a `static` on its own.
+    This wrapper is `Sync` and can be built at compile time, so it needs neither the standard library's locks nor any initialization at runtime.
+    Every access goes through `lock()`, which spins until the allocator is available.
+*/
+pub struct StaticBuddyAllocator<const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    /// Whether a guard currently gives access to the allocator.
+    locked: AtomicBool,
+
+    /// Only accessed through a guard.
+    alloc: BuddyAllocator<M, B>,
+
+}
+
+// The allocator is only ever accessed through a guard, and there is at most one guard at a time
+unsafe impl<const M: usize, const B: usize> Sync for StaticBuddyAllocator<M, B>
+where
+    [(); M / B]:,
+    BuddyAllocator<M, B>: Send,
+{}
+
+impl<const M: usize, const B: usize> StaticBuddyAllocator<M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Create a new unlocked allocator with a zero-initialized heap.
+    /// Like `BuddyAllocator::new_static()`, pointers to allocated blocks are only valid as long as the allocator is not moved, which a `static` never is.
+    pub const fn new_static() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            alloc: BuddyAllocator::new_static()
+        }
+    }
+
+}
+
+impl<const M: usize, const B: usize> StaticBuddyAllocator<M, B>
+where
+    [(); M / B]:
+{
+
+    /// Spin until the allocator is available and return a guard that gives exclusive access to it.
+    /// Locking the allocator again while the guard is alive, from the same thread, spins forever.
+    pub fn lock(&self) -> StaticBuddyAllocatorGuard<'_, M, B> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+
+    /// Return a guard that gives exclusive access to the allocator, or `None` if it's already locked.
+    pub fn try_lock(&self) -> Option<StaticBuddyAllocatorGuard<'_, M, B>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| StaticBuddyAllocatorGuard { owner: self, _not_sync: PhantomData })
+    }
+
+}
+
+
+/// Exclusive access to the allocator of a `StaticBuddyAllocator`, which is unlocked when the guard is dropped.
+pub struct StaticBuddyAllocatorGuard<'a, const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    owner: &'a StaticBuddyAllocator<M, B>,
+
+    /// Sharing the guard between threads would share the allocator, which is not `Sync`.
+    _not_sync: PhantomData<*const ()>,
+
+}
+
+impl<const M: usize, const B: usize> Deref for StaticBuddyAllocatorGuard<'_, M, B>
+where
+    [(); M / B]:
+{
+
+    type Target = BuddyAllocator<M, B>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.owner.alloc
+    }
+
+}
+
+impl<const M: usize, const B: usize> Drop for StaticBuddyAllocatorGuard<'_, M, B>
+where
+    [(); M / B]:
+{
+
+    fn drop(&mut self) {
+        self.owner.locked.store(false, Ordering::Release);
+    }
+
+}
@@ -0,0 +1,233 @@
+use std::cell::UnsafeCell;
+use std::mem;
+use std::ptr::NonNull;
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{alloc_table::AllocTable, buddy_allocator::BuddyAllocator, coalescing::CoalescingStats, errors::{AllocError, FreeError, IntegrityError}, handle::BlockHandle};
+
+
+/// The bookkeeping of a `SubAllocator` managing `N` zero-order blocks of `B` bytes.
+struct SubState<const B: usize, const N: usize> {
+
+    /// A binary tree that keeps track of the allocated and free blocks of the child heap.
+    alloc_table: AllocTable<B, N>,
+
+    /// The total amount of free memory in the child heap.
+    total_free: usize,
+
+}
+
+
+/**
+    A buddy allocator managing a single block of order `ORDER` reserved from a parent `BuddyAllocator`.
+
+    The child heap is `B << ORDER` bytes long and shares the zero-order block size of its parent.
+    It has its own allocation tree and statistics, and can be emptied independently of the parent.
+    When the child is dropped, its block goes back to the parent, which invalidates every block still allocated from the child.
+*/
+pub struct SubAllocator<'a, const M: usize, const B: usize, const ORDER: usize>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+    [(); 1 << ORDER]:,
+{
+
+    /// The allocator the child heap was reserved from.
+    parent: &'a BuddyAllocator<M, B>,
+
+    /// The block of the parent that holds the child heap.
+    block: BlockHandle,
+
+    /// Start of the child heap.
+    base: NonNull<u8>,
+
+    /// The bookkeeping of the child heap. It's boxed because the allocation tree may be too large for the stack.
+    state: Box<UnsafeCell<SubState<B, { 1 << ORDER }>>>,
+
+}
+
+impl<'a, const M: usize, const B: usize, const ORDER: usize> SubAllocator<'a, M, B, ORDER>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+    [(); 1 << ORDER]:,
+{
+
+    /// Size of the child heap in bytes.
+    const SIZE: usize = B << ORDER;
+
+
+    /// Reserve a block of order `ORDER` from `parent` and build an empty child heap on top of it.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub(crate) fn new(parent: &'a BuddyAllocator<M, B>) -> Result<Self, AllocError> {
+
+        if Self::SIZE > M {
+            return Err(AllocError::OutOfMemory);
+        }
+
+        let block = parent.alloc_handle(Self::SIZE)?;
+
+        let mut state = Box::<UnsafeCell<SubState<B, { 1 << ORDER }>>>::new_uninit();
+        let this = UnsafeCell::raw_get(state.as_mut_ptr());
+
+        let state = unsafe {
+            AllocTable::init_in_place(&raw mut (*this).alloc_table);
+            (&raw mut (*this).total_free).write(Self::SIZE);
+            state.assume_init()
+        };
+
+        Ok(Self {
+            parent,
+            block,
+            base: parent.resolve(block),
+            state
+        })
+    }
+
+
+    /// Return the bookkeeping of the child heap for reading.
+    fn state(&self) -> &SubState<B, { 1 << ORDER }> {
+        // Mutable references to the state never outlive the method that created them, and the child is not `Sync`
+        unsafe { &*self.state.get() }
+    }
+
+
+    /// Return the bookkeeping of the child heap for writing.
+    ///
+    /// # Safety
+    ///
+    /// The returned reference must not outlive the public method that requested it, which must not access the state in any other way meanwhile.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn state_mut(&self) -> &mut SubState<B, { 1 << ORDER }> {
+        unsafe { &mut *self.state.get() }
+    }
+
+
+    /// Return the handle of the parent block that holds the child heap.
+    pub const fn block(&self) -> BlockHandle {
+        self.block
+    }
+
+
+    /// Allocate a memory block big enough to store at least the size of `T`.
+    /// Return a pointer to the start of the allocated block.
+    pub fn alloc<T>(&self) -> Result<NonNull<T>, AllocError> {
+        self.alloc_bytes(mem::size_of::<T>())
+            .map(NonNull::cast)
+    }
+
+
+    /// Allocate a memory block of the child heap big enough to store at least `size` bytes.
+    /// Return a pointer to the start of the allocated block.
+    pub fn alloc_bytes(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+
+        let state = unsafe { self.state_mut() };
+
+        if size == 0 {
+            Err(AllocError::ZeroAllocation)
+
+        } else if size > state.total_free {
+            Err(AllocError::OutOfMemory)
+
+        } else if let Some((offset, allocated)) = state.alloc_table.alloc(size) {
+            state.total_free -= allocated;
+            Ok(unsafe { self.base.byte_add(offset) })
+
+        } else {
+            Err(AllocError::OutOfMemory)
+        }
+    }
+
+
+    /// Free the memory block found at `ptr`.
+    /// Note that the block must have been allocated through this child allocator.
+    pub fn free_nonnull<T>(&self, ptr: NonNull<T>) -> Result<(), FreeError> {
+
+        let state = unsafe { self.state_mut() };
+
+        // Only compare addresses, so that `ptr` may come from anywhere
+        let base_address = self.base.as_ptr().addr();
+        let address = ptr.as_ptr().addr();
+
+        if address < base_address || address >= base_address + Self::SIZE {
+            return Err(FreeError::FreeOutOfBounds);
+        }
+
+        state.total_free += state.alloc_table.free(address - base_address)?;
+        Ok(())
+    }
+
+
+    /// Walk the whole allocation tree of the child heap and check that it's consistent.
+    pub fn check_integrity(&self) -> Result<(), IntegrityError> {
+
+        let state = self.state();
+        let free = state.alloc_table.check_integrity()?;
+
+        if free != state.total_free {
+            return Err(IntegrityError::FreeMemoryMismatch { recorded: state.total_free, actual: free });
+        }
+
+        Ok(())
+    }
+
+
+    /// Return how many blocks of the child heap have been split and merged.
+    pub fn coalescing_stats(&self) -> CoalescingStats {
+        self.state().alloc_table.stats()
+    }
+
+
+    /// Return the total amount of free memory in the child heap.
+    pub fn total_free(&self) -> usize {
+        self.state().total_free
+    }
+
+
+    /// Return the total size of the child heap.
+    pub const fn heap_size(&self) -> usize {
+        Self::SIZE
+    }
+
+
+    /// Return the size of allocated memory in the child heap.
+    pub fn total_allocated(&self) -> usize {
+        self.heap_size() - self.total_free()
+    }
+
+
+    /// Free the entirety of the child heap. The parent is not affected.
+    ///
+    /// # Safety
+    ///
+    /// This function is inherently unsafe because it will invalidate all pointers to blocks previously allocated from the child.
+    pub unsafe fn free_all(&self) {
+
+        let state = unsafe { self.state_mut() };
+
+        state.alloc_table.clear();
+        state.total_free = Self::SIZE;
+    }
+
+}
+
+impl<const M: usize, const B: usize, const ORDER: usize> Drop for SubAllocator<'_, M, B, ORDER>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+    [(); 1 << ORDER]:,
+{
+
+    fn drop(&mut self) {
+        // The block was allocated from the parent when the child was created, and errors cannot be reported from a destructor anyway
+        let _ = self.parent.free_handle(self.block);
+    }
+
+}
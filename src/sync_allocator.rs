@@ -0,0 +1,399 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{buddy_allocator::BuddyAllocator, errors::{AllocError, FreeError}};
+
+
+/// An allocation waiting for memory to be freed.
+struct Waiter {
+
+    /// Identifies the allocation in the queue.
+    ticket: u64,
+
+    /// Wakes the task waiting for the allocation, if it comes from a future.
+    waker: Option<Waker>,
+
+}
+
+
+/// Allocations waiting for memory, served in FIFO order.
+struct WaitQueue {
+
+    waiters: VecDeque<Waiter>,
+
+    next_ticket: u64,
+
+}
+
+impl WaitQueue {
+
+    const fn new() -> Self {
+        Self {
+            waiters: VecDeque::new(),
+            next_ticket: 0
+        }
+    }
+
+
+    /// Add an allocation at the end of the queue and return its ticket.
+    fn enqueue(&mut self, waker: Option<Waker>) -> u64 {
+
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+
+        self.waiters.push_back(Waiter { ticket, waker });
+        ticket
+    }
+
+
+    /// Return whether the allocation with the given ticket is at the head of the queue, which means it may try to allocate.
+    fn is_head(&self, ticket: u64) -> bool {
+        self.waiters.front().is_some_and(|waiter| waiter.ticket == ticket)
+    }
+
+
+    /// Remove the allocation with the given ticket from the queue.
+    fn dequeue(&mut self, ticket: u64) {
+        self.waiters.retain(|waiter| waiter.ticket != ticket);
+    }
+
+
+    /// Wake the task waiting for the allocation at the head of the queue, if it comes from a future.
+    /// Threads are woken through the condition variable.
+    fn wake_head(&self) {
+        if let Some(waker) = self.waiters.front().and_then(|waiter| waiter.waker.as_ref()) {
+            waker.wake_by_ref();
+        }
+    }
+
+}
+
+
+/**
+    A thread-safe wrapper around a `BuddyAllocator`, whose allocations can wait for memory to be freed instead of failing.
+
+    Waiting allocations are served in FIFO order: only the oldest one may try to allocate, every time a block is freed.
+    Allocations that don't wait, like `alloc_bytes()`, are not queued.
+
+    The allocator is `Sync`, so `new_static()` can build it in a plain `static`.
+
+    Every method locks the allocator, and the lock is not reentrant.
+    Code that runs under the lock, like the closure passed to `with()` and the out-of-memory and pressure handlers of the underlying allocator, must not call back into the `SyncBuddyAllocator`, or it deadlocks.
+*/
+pub struct SyncBuddyAllocator<const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    /// Only accessed while `queue` is locked.
+    alloc: BuddyAllocator<M, B>,
+
+    /// Allocations waiting for memory. Its lock also guards `alloc`.
+    queue: Mutex<WaitQueue>,
+
+    /// Notified whenever the head of the queue may be able to allocate, to wake the threads waiting in `alloc_wait()`.
+    changed: Condvar,
+
+}
+
+// The allocator is only ever accessed while the queue is locked, which serializes every access across threads
+unsafe impl<const M: usize, const B: usize> Sync for SyncBuddyAllocator<M, B>
+where
+    [(); M / B]:,
+    BuddyAllocator<M, B>: Send,
+{}
+
+impl<const M: usize, const B: usize> SyncBuddyAllocator<M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    /// Create a new allocator. Optionally, the heap can be initialized with `0` bytes.
+    /// The allocator is built directly on the heap, so its size is not limited by the stack size.
+    pub fn new(zero_initialized: bool) -> Pin<Box<Self>> {
+
+        let mut res = Box::<Self>::new_uninit();
+        let this = res.as_mut_ptr();
+
+        unsafe {
+            BuddyAllocator::init_in_place(&raw mut (*this).alloc, zero_initialized);
+            (&raw mut (*this).queue).write(Mutex::new(WaitQueue::new()));
+            (&raw mut (*this).changed).write(Condvar::new());
+
+            Box::into_pin(res.assume_init())
+        }
+    }
+
+
+    /// Create a new allocator with a zero-initialized heap, meant to be stored in a `static` variable.
+    /// Like `BuddyAllocator::new_static()`, pointers to allocated blocks are only valid as long as the allocator is not moved, which a `static` never is.
+    pub const fn new_static() -> Self {
+        Self {
+            alloc: BuddyAllocator::new_static(),
+            queue: Mutex::new(WaitQueue::new()),
+            changed: Condvar::new()
+        }
+    }
+
+
+    /// Lock the allocator. A panic while holding the lock cannot leave the allocator inconsistent, so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, WaitQueue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+
+    /// Wake the allocation at the head of the queue, whether it's a task or a thread.
+    fn notify(&self, queue: &WaitQueue) {
+        queue.wake_head();
+        self.changed.notify_all();
+    }
+
+
+    /// Try to allocate for the allocation with the given ticket, assuming it's at the head of the locked queue.
+    /// Leave it in the queue if there's still not enough memory. Otherwise, remove it and let the next allocation try.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn try_serve(&self, queue: &mut WaitQueue, ticket: u64, size: usize) -> Option<Result<NonNull<u8>, AllocError>> {
+
+        match self.alloc.alloc_bytes(size) {
+
+            // Reserving memory while the allocation waits may leave no room for it at all
+            Err(AllocError::OutOfMemory) if self.alloc.fits_when_empty(size) => None,
+
+            res => {
+                // There may be enough memory left for the next allocation as well
+                queue.dequeue(ticket);
+                self.notify(queue);
+                Some(res)
+            }
+        }
+    }
+
+
+    /// Run `f` with exclusive access to the underlying allocator.
+    /// Waiting allocations are given a chance to allocate afterwards, in case `f` freed memory.
+    ///
+    /// The lock is held while `f` runs, so `f` must use the allocator it's given rather than this `SyncBuddyAllocator`, whose methods would deadlock.
+    /// The same goes for the out-of-memory and pressure handlers registered through `f`: they run under the lock too, so they must not touch this `SyncBuddyAllocator` either.
+    pub fn with<R>(&self, f: impl FnOnce(&BuddyAllocator<M, B>) -> R) -> R {
+
+        let queue = self.lock();
+        let res = f(&self.alloc);
+
+        self.notify(&queue);
+        res
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes, without waiting.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_bytes(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+
+        let _queue = self.lock();
+        self.alloc.alloc_bytes(size)
+    }
+
+
+    /// Free the memory block found at `ptr` and let the oldest waiting allocation try again.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn free_nonnull<T>(&self, ptr: NonNull<T>) -> Result<(), FreeError> {
+
+        let queue = self.lock();
+        let res = self.alloc.free_nonnull(ptr);
+
+        if res.is_ok() {
+            self.notify(&queue);
+        }
+
+        res
+    }
+
+
+    /// Return the total amount of free memory in the heap.
+    pub fn total_free(&self) -> usize {
+
+        let _queue = self.lock();
+        self.alloc.total_free()
+    }
+
+
+    /// Return a future that allocates a memory block big enough to store at least `size` bytes, waiting for memory to be freed if needed.
+    /// The future only fails with errors that waiting cannot fix.
+    /// Requests that could not be served even once every block is freed, because they are larger than the heap or than any block left between the reserved ranges,
+    /// fail with `AllocError::OutOfMemory` instead of waiting forever.
+    pub fn alloc_async(&self, size: usize) -> AllocFuture<'_, M, B> {
+        AllocFuture {
+            alloc: self,
+            size,
+            ticket: None,
+            done: false
+        }
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes, blocking the thread until enough memory is freed.
+    /// Fail with `AllocError::OutOfMemory` if there's still not enough memory after `timeout`, if any.
+    /// Like `alloc_async()`, fail right away if the request could not be served even once every block is freed.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_wait(&self, size: usize, timeout: Option<Duration>) -> Result<NonNull<u8>, AllocError> {
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut queue = self.lock();
+
+        if !self.alloc.fits_when_empty(size) {
+            return Err(AllocError::OutOfMemory);
+        }
+
+        if queue.waiters.is_empty() {
+            match self.alloc.alloc_bytes(size) {
+                Err(AllocError::OutOfMemory) => (),
+                res => return res
+            }
+        }
+
+        let ticket = queue.enqueue(None);
+
+        loop {
+            if queue.is_head(ticket) {
+                if let Some(res) = self.try_serve(&mut queue, ticket, size) {
+                    return res;
+                }
+            }
+
+            queue = match deadline {
+
+                None => self.changed.wait(queue).unwrap_or_else(PoisonError::into_inner),
+
+                Some(deadline) => {
+
+                    let now = Instant::now();
+                    if now >= deadline {
+                        let was_head = queue.is_head(ticket);
+                        queue.dequeue(ticket);
+                        if was_head {
+                            self.notify(&queue);
+                        }
+                        return Err(AllocError::OutOfMemory);
+                    }
+
+                    self.changed.wait_timeout(queue, deadline - now).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+    }
+
+}
+
+
+/// A future returned by `SyncBuddyAllocator::alloc_async()`, which resolves once the allocation succeeds.
+/// Dropping the future before it resolves gives up its place in the queue.
+/// Like most futures, it panics if polled again after it resolved, instead of allocating another block.
+pub struct AllocFuture<'a, const M: usize, const B: usize>
+where
+    [(); M / B]:
+{
+
+    alloc: &'a SyncBuddyAllocator<M, B>,
+
+    size: usize,
+
+    /// Place of the allocation in the queue, once it had to wait.
+    ticket: Option<u64>,
+
+    /// Whether the future already resolved.
+    done: bool,
+
+}
+
+impl<const M: usize, const B: usize> Future for AllocFuture<'_, M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    type Output = Result<NonNull<u8>, AllocError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+
+        assert!(!self.done, "AllocFuture polled after completion");
+
+        let size = self.size;
+        let alloc = self.alloc;
+        let mut queue = alloc.lock();
+
+        let ticket = match self.ticket {
+
+            Some(ticket) => ticket,
+
+            None => {
+
+                if !alloc.alloc.fits_when_empty(size) {
+                    self.done = true;
+                    return Poll::Ready(Err(AllocError::OutOfMemory));
+                }
+
+                if queue.waiters.is_empty() {
+                    match alloc.alloc.alloc_bytes(size) {
+                        Err(AllocError::OutOfMemory) => (),
+                        res => {
+                            self.done = true;
+                            return Poll::Ready(res);
+                        }
+                    }
+                }
+
+                self.ticket = Some(queue.enqueue(Some(cx.waker().clone())));
+                return Poll::Pending;
+            }
+        };
+
+        if queue.is_head(ticket) {
+            if let Some(res) = alloc.try_serve(&mut queue, ticket, size) {
+                self.ticket = None;
+                self.done = true;
+                return Poll::Ready(res);
+            }
+        }
+
+        // The task may have moved to another executor since it was last polled
+        if let Some(waiter) = queue.waiters.iter_mut().find(|waiter| waiter.ticket == ticket) {
+            waiter.waker = Some(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+
+}
+
+impl<const M: usize, const B: usize> Drop for AllocFuture<'_, M, B>
+where
+    [(); M / B]:
+{
+
+    fn drop(&mut self) {
+
+        let Some(ticket) = self.ticket else { return };
+
+        let mut queue = self.alloc.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let was_head = queue.is_head(ticket);
+        queue.dequeue(ticket);
+
+        // Let the next allocation take its turn
+        if was_head {
+            queue.wake_head();
+            self.alloc.changed.notify_all();
+        }
+    }
+
+}
@@ -0,0 +1,116 @@
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr::NonNull;
+
+use libc::{EOWNERDEAD, MAP_FAILED, MAP_SHARED, MS_SYNC, PROT_READ, PROT_WRITE, PTHREAD_MUTEX_ROBUST, PTHREAD_PROCESS_SHARED};
+use libc::{mmap, msync, munmap, pthread_mutex_consistent, pthread_mutex_init, pthread_mutex_lock, pthread_mutex_unlock};
+use libc::{pthread_mutexattr_destroy, pthread_mutexattr_init, pthread_mutexattr_setpshared, pthread_mutexattr_setrobust, pthread_mutexattr_t};
+
+
+/// Map `len` bytes of the file referred to by `fd` into memory as a shared, readable and writable mapping.
+pub fn map_shared(fd: c_int, len: usize) -> io::Result<NonNull<u8>> {
+
+    let addr = unsafe {
+        mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)
+    };
+
+    if addr == MAP_FAILED {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { NonNull::new_unchecked(addr as *mut u8) })
+    }
+}
+
+
+/// Synchronously write back the `len` bytes of the mapping that starts at `addr`.
+pub fn sync(addr: NonNull<u8>, len: usize) -> io::Result<()> {
+
+    if unsafe { msync(addr.as_ptr() as *mut c_void, len, MS_SYNC) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+
+/// Remove the mapping of `len` bytes that starts at `addr`.
+pub fn unmap(addr: NonNull<u8>, len: usize) -> io::Result<()> {
+
+    if unsafe { munmap(addr.as_ptr() as *mut c_void, len) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+
+
+/// A `pthread_mutex_t`, with the size and alignment of the target platform.
+pub type RawMutex = libc::pthread_mutex_t;
+
+
+/// Convert the return code of a pthread function into a result.
+fn check(code: c_int) -> io::Result<()> {
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(code))
+    }
+}
+
+
+/// Initialize a robust mutex that can be shared between processes.
+/// 
+/// # Safety
+/// 
+/// `mutex` must point to valid, writable memory that no one else is using.
+pub unsafe fn init_shared_mutex(mutex: *mut RawMutex) -> io::Result<()> {
+
+    let mut attr = MaybeUninit::<pthread_mutexattr_t>::uninit();
+
+    unsafe {
+        check(pthread_mutexattr_init(attr.as_mut_ptr()))?;
+        let attr = attr.assume_init_mut();
+
+        let res = check(pthread_mutexattr_setpshared(attr, PTHREAD_PROCESS_SHARED))
+            .and_then(|_| check(pthread_mutexattr_setrobust(attr, PTHREAD_MUTEX_ROBUST)))
+            .and_then(|_| check(pthread_mutex_init(mutex, attr)));
+
+        pthread_mutexattr_destroy(attr);
+        res
+    }
+}
+
+
+/// Lock a mutex initialized through `init_shared_mutex()`.
+/// Return `true` if the previous owner died while holding the lock, in which case the data it protects may be inconsistent.
+/// 
+/// # Safety
+/// 
+/// `mutex` must point to an initialized mutex.
+pub unsafe fn lock_shared_mutex(mutex: *mut RawMutex) -> io::Result<bool> {
+
+    match unsafe { pthread_mutex_lock(mutex) } {
+
+        0 => Ok(false),
+
+        EOWNERDEAD => {
+            // Take over the lock. The caller is responsible for restoring the consistency of the protected data.
+            check(unsafe { pthread_mutex_consistent(mutex) })?;
+            Ok(true)
+        },
+
+        code => Err(io::Error::from_raw_os_error(code))
+    }
+}
+
+
+/// Unlock a mutex locked through `lock_shared_mutex()`.
+/// 
+/// # Safety
+/// 
+/// `mutex` must point to an initialized mutex owned by the calling thread.
+pub unsafe fn unlock_shared_mutex(mutex: *mut RawMutex) -> io::Result<()> {
+    check(unsafe { pthread_mutex_unlock(mutex) })
+}
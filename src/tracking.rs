@@ -0,0 +1,117 @@
+use std::backtrace::Backtrace;
+use std::panic::Location;
+use std::sync::Arc;
+
+
+/// Where an allocator function was called from.
+#[derive(Debug, Clone)]
+pub struct CallSite {
+
+    /// Source location of the call.
+    pub location: &'static Location<'static>,
+
+    /// Full backtrace of the call, if backtrace capture is enabled.
+    pub backtrace: Option<Arc<Backtrace>>,
+
+}
+
+impl CallSite {
+
+    /// Capture the call site of the innermost caller that isn't annotated with `#[track_caller]`.
+    #[track_caller]
+    fn capture(with_backtrace: bool) -> Self {
+        Self {
+            location: Location::caller(),
+            backtrace: with_backtrace.then(|| Arc::new(Backtrace::force_capture()))
+        }
+    }
+
+}
+
+
+/// The history of the most recent allocation that started at a given block offset.
+#[derive(Debug, Clone)]
+pub struct AllocationInfo {
+
+    /// Who allocated the block.
+    pub allocated_at: CallSite,
+
+    /// Who freed the block, if it has been freed.
+    pub freed_at: Option<CallSite>,
+
+}
+
+
+/// Side metadata recording the call sites of allocations, indexed by zero-order block.
+pub(crate) struct Tracker<const N: usize> {
+
+    sites: [Option<AllocationInfo>; N],
+
+    /// Whether to capture a full backtrace in addition to the source location.
+    capture_backtraces: bool,
+
+}
+
+impl<const N: usize> Tracker<N> {
+
+    pub const fn new() -> Self {
+        Self {
+            sites: [const { None }; N],
+            capture_backtraces: false
+        }
+    }
+
+
+    /// Initialize an empty tracker at `this` without building it on the stack first.
+    /// 
+    /// # Safety
+    /// 
+    /// `this` must be valid for writes.
+    pub unsafe fn init_in_place(this: *mut Self) {
+        unsafe {
+            let sites = (&raw mut (*this).sites).cast::<Option<AllocationInfo>>();
+            for index in 0..N {
+                sites.add(index).write(None);
+            }
+            (&raw mut (*this).capture_backtraces).write(false);
+        }
+    }
+
+
+    pub fn set_capture_backtraces(&mut self, capture_backtraces: bool) {
+        self.capture_backtraces = capture_backtraces;
+    }
+
+
+    /// Record that the block at the given zero-order block index was allocated by the caller.
+    #[track_caller]
+    pub fn record_alloc(&mut self, index: usize) {
+        self.sites[index] = Some(AllocationInfo {
+            allocated_at: CallSite::capture(self.capture_backtraces),
+            freed_at: None
+        });
+    }
+
+
+    /// Record that the block at the given zero-order block index was freed by the caller.
+    #[track_caller]
+    pub fn record_free(&mut self, index: usize) {
+        let capture_backtraces = self.capture_backtraces;
+        if let Some(info) = &mut self.sites[index] {
+            info.freed_at = Some(CallSite::capture(capture_backtraces));
+        }
+    }
+
+
+    pub fn get(&self, index: usize) -> Option<&AllocationInfo> {
+        self.sites[index].as_ref()
+    }
+
+
+    /// Forget every recorded allocation.
+    pub fn clear(&mut self) {
+        self.sites = [const { None }; N];
+    }
+
+}
+
@@ -0,0 +1,171 @@
+use std::ptr::NonNull;
+
+use const_assert::{Assert, IsTrue};
+
+use crate::{buddy_allocator::BuddyAllocator, errors::{AllocError, FreeError}};
+
+
+/// A heap that can serve as a zone of a `ZonedAllocator`.
+/// Zones may have different heap sizes and zero-order block sizes.
+pub trait Zone {
+
+    /// Allocate a memory block big enough to store at least `size` bytes from the zone.
+    fn alloc_bytes(&self, size: usize) -> Result<NonNull<u8>, AllocError>;
+
+    /// Free the memory block of the zone found at `ptr`.
+    fn free_nonnull(&self, ptr: NonNull<u8>) -> Result<(), FreeError>;
+
+    /// Return whether `ptr` lies inside the heap of the zone.
+    fn contains(&self, ptr: NonNull<u8>) -> bool;
+
+    /// Return the total amount of free memory in the zone.
+    fn total_free(&self) -> usize;
+
+}
+
+impl<const M: usize, const B: usize> Zone for BuddyAllocator<M, B>
+where
+    Assert<{ M.is_power_of_two() }>: IsTrue,
+    Assert<{ B.is_power_of_two() }>: IsTrue,
+    Assert<{ M.is_multiple_of(B) }>: IsTrue,
+    [(); M / B]:,
+{
+
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn alloc_bytes(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        BuddyAllocator::alloc_bytes(self, size)
+    }
+
+
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    fn free_nonnull(&self, ptr: NonNull<u8>) -> Result<(), FreeError> {
+        BuddyAllocator::free_nonnull(self, ptr)
+    }
+
+
+    fn contains(&self, ptr: NonNull<u8>) -> bool {
+        self.handle_of(ptr).is_some()
+    }
+
+
+    fn total_free(&self) -> usize {
+        BuddyAllocator::total_free(self)
+    }
+
+}
+
+
+/**
+    An allocator that groups `Z` zones, such as "DMA", "normal" and "high" memory, each backed by its own heap.
+
+    Allocation requests carry a zone mask, where bit `i` allows zone `i` to serve the request.
+    The allowed zones are tried in a configurable fallback order, and frees are routed to the zone whose heap contains the freed block.
+*/
+pub struct ZonedAllocator<'a, const Z: usize>
+where
+    Assert<{ Z <= u32::BITS as usize }>: IsTrue,
+{
+
+    zones: [&'a dyn Zone; Z],
+
+    /// Indices of the zones in the order they are tried.
+    fallback_order: [usize; Z],
+
+}
+
+impl<'a, const Z: usize> ZonedAllocator<'a, Z>
+where
+    Assert<{ Z <= u32::BITS as usize }>: IsTrue,
+{
+
+    /// Mask that allows every zone.
+    pub const ALL_ZONES: u32 = u32::MAX;
+
+
+    /// Group the given zones, which are tried in index order by default.
+    /// The heaps of the zones must not overlap.
+    pub fn new(zones: [&'a dyn Zone; Z]) -> Self {
+        Self {
+            zones,
+            fallback_order: std::array::from_fn(|i| i)
+        }
+    }
+
+
+    /// Choose the order the zones are tried in, as a permutation of the zone indices.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `order` is not a permutation of the zone indices.
+    pub fn set_fallback_order(&mut self, order: [usize; Z]) {
+
+        let mut seen = [false; Z];
+        for &zone in &order {
+            assert!(zone < Z && !seen[zone], "The fallback order must be a permutation of the zone indices");
+            seen[zone] = true;
+        }
+
+        self.fallback_order = order;
+    }
+
+
+    /// Return the order the zones are tried in.
+    pub const fn fallback_order(&self) -> [usize; Z] {
+        self.fallback_order
+    }
+
+
+    /// Return the zone at the given index.
+    pub fn zone(&self, index: usize) -> &'a dyn Zone {
+        self.zones[index]
+    }
+
+
+    /// Allocate a memory block big enough to store at least `size` bytes from the first zone allowed by `zone_mask` that can serve it.
+    /// Return a pointer to the start of the allocated block and the index of the zone it was allocated from.
+    /// If no zone can serve the request, return the error of the last zone tried, or `AllocError::OutOfMemory` if the mask allows no zone.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn alloc_bytes(&self, size: usize, zone_mask: u32) -> Result<(NonNull<u8>, usize), AllocError> {
+
+        let mut res = Err(AllocError::OutOfMemory);
+
+        for &zone in self.fallback_order.iter().filter(|&&zone| zone_mask & (1 << zone) != 0) {
+
+            res = self.zones[zone].alloc_bytes(size)
+                .map(|ptr| (ptr, zone));
+
+            match res {
+                Ok(_) | Err(AllocError::ZeroAllocation) => break,
+                Err(_) => ()
+            }
+        }
+
+        res
+    }
+
+
+    /// Free the memory block found at `ptr` through the zone whose heap contains it.
+    /// Fail with `FreeError::FreeOutOfBounds` if no zone contains it.
+    #[cfg_attr(feature = "track-allocations", track_caller)]
+    pub fn free_nonnull<T>(&self, ptr: NonNull<T>) -> Result<(), FreeError> {
+
+        let zone = self.zone_of(ptr).ok_or(FreeError::FreeOutOfBounds)?;
+        self.zones[zone].free_nonnull(ptr.cast())
+    }
+
+
+    /// Return the index of the zone whose heap contains `ptr`, if any.
+    pub fn zone_of<T>(&self, ptr: NonNull<T>) -> Option<usize> {
+        self.zones.iter().position(|zone| zone.contains(ptr.cast()))
+    }
+
+
+    /// Return the total amount of free memory in the zones allowed by `zone_mask`.
+    pub fn total_free(&self, zone_mask: u32) -> usize {
+        (0..Z)
+            .filter(|&zone| zone_mask & (1 << zone) != 0)
+            .map(|zone| self.zones[zone].total_free())
+            .sum()
+    }
+
+}